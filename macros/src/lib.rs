@@ -0,0 +1,685 @@
+use proc_macro::TokenStream;
+use quote::{format_ident, quote};
+use syn::visit_mut::{self, VisitMut};
+use syn::{
+    Block, Data, DeriveInput, Expr, ExprAsync, ExprClosure, ExprLit, Fields, Item, ItemFn, Lit,
+    Local, Pat, PatIdent, ReturnType, Stmt, Type, parse_macro_input, parse_quote,
+};
+
+/// Types which are tracked with a single `Tag`, rather than a companion tag struct.
+const PRIMITIVES: &[&str] = &[
+    "u8", "u16", "u32", "u64", "u128", "usize", "i8", "i16", "i32", "i64", "i128", "isize", "f32",
+    "f64", "bool", "char", "String", "str",
+];
+
+/// Generates the companion `<Name>Tag` struct for a struct under analysis, following
+/// the design notes in `main.rs`:
+///
+/// struct S { a: prim, b: Inner }
+///     -->
+/// struct STag { a_tag: Tag, b_tag: InnerTag }
+///
+/// Every primitive field is mirrored by a `Tag`, and every other field of type `Foo`
+/// by a `FooTag` (which is expected to be derived as well). A `new` constructor
+/// accepting the per-field tags, in declaration order, is generated alongside it,
+/// as well as a `FieldTags` implementation yielding the dotted path of every
+/// primitive field (e.g. `b.a`).
+///
+/// Only structs with named fields are supported for now.
+#[proc_macro_derive(AtiTagged)]
+pub fn derive_ati_tagged(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_ati_tagged(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_ati_tagged(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "AtiTagged only supports structs with named fields",
+                ));
+            }
+        },
+        Data::Enum(_) | Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "AtiTagged only supports structs",
+            ));
+        }
+    };
+
+    let vis = &input.vis;
+    let tag_ident = format_ident!("{}Tag", input.ident);
+
+    let mut tag_fields = Vec::new();
+    let mut tag_types = Vec::new();
+    let mut field_tags = Vec::new();
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let tag_field = format_ident!("{}_tag", ident);
+        let path = ident.to_string();
+        let (tag_type, is_primitive) = tag_type(&field.ty)?;
+
+        field_tags.push(if is_primitive {
+            quote! { tags.push((#path.to_owned(), &self.#tag_field)); }
+        } else {
+            quote! {
+                for (inner, tag) in ::hand_analyze::tag::FieldTags::field_tags(&self.#tag_field) {
+                    tags.push((format!("{}.{}", #path, inner), tag));
+                }
+            }
+        });
+        tag_fields.push(tag_field);
+        tag_types.push(tag_type);
+    }
+
+    Ok(quote! {
+        #vis struct #tag_ident {
+            #( #vis #tag_fields: #tag_types, )*
+        }
+
+        impl #tag_ident {
+            #vis fn new(#( #tag_fields: #tag_types ),*) -> Self {
+                #tag_ident { #( #tag_fields ),* }
+            }
+        }
+
+        impl ::hand_analyze::tag::FieldTags for #tag_ident {
+            fn field_tags(&self) -> Vec<(String, &::hand_analyze::tag::Tag)> {
+                let mut tags = Vec::new();
+                #( #field_tags )*
+                tags
+            }
+        }
+    })
+}
+
+/// Maps the type of a field to the type of the tag which tracks it, and whether
+/// the field is a primitive (tracked by a single `Tag`).
+fn tag_type(ty: &Type) -> syn::Result<(proc_macro2::TokenStream, bool)> {
+    match ty {
+        Type::Reference(reference) => tag_type(&reference.elem),
+        Type::Path(path) if path.qself.is_none() => {
+            let ident = &path.path.segments.last().unwrap().ident;
+            if PRIMITIVES.iter().any(|prim| ident == prim) {
+                Ok((quote!(::hand_analyze::tag::Tag), true))
+            } else {
+                let tag_ident = format_ident!("{}Tag", ident);
+                Ok((quote!(#tag_ident), false))
+            }
+        }
+        _ => Err(syn::Error::new_spanned(
+            ty,
+            "AtiTagged cannot determine the tag type of this field",
+        )),
+    }
+}
+
+/// Instruments a function as the analysis site with the given id, following the
+/// design notes in `main.rs`. The function is expected to have an `ati: &mut ATI`
+/// binding in scope, e.g. as a parameter:
+///
+/// #[ati_site(3)]
+/// fn f(x: u32, ati: &mut ATI) -> u32 { let a = x + 1; a }
+///     -->
+/// fn f(x: u32, ati: &mut ATI) -> u32 {
+///     let mut site = ati.get_site("3");
+///     let __ati_ret = (|| -> u32 {
+///         let a = x + 1;
+///         let a_tag = ati.tracked(stringify!(a), &a, &mut site);
+///         a
+///     })();
+///     ati.update_site(site).unwrap();
+///     __ati_ret
+/// }
+///
+/// Only `let` bindings of a single variable to a literal, a variable or
+/// arithmetic over those are tracked, as their value is created by the binding.
+/// Bindings of calls, and of any other expression, are left untouched, and so
+/// are closures and nested items.
+///
+/// The body runs in a closure so that a `return` or an early exit through `?`
+/// only leaves the closure, and the site is updated however the body is left.
+/// Hence async functions are not supported, as the closure cannot `.await`, and
+/// neither are bodies returning a borrow of a `&mut` parameter, which cannot
+/// escape the closure.
+#[proc_macro_attribute]
+pub fn ati_site(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    match expand_ati_site(attr.into(), item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_ati_site(
+    attr: proc_macro2::TokenStream,
+    mut item: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let id = site_id(attr)?;
+    instrument(
+        &mut item,
+        quote! {
+            #[allow(unused_mut)]
+            let mut site = ati.get_site(#id);
+        },
+        quote! { ati.update_site(site).unwrap(); },
+    )?;
+    Ok(quote!(#item))
+}
+
+/// Instruments `main` as the entry point of the analysis, following the design
+/// notes in `main.rs`: it creates the `ati` analysis and the `main` site, tracks
+/// its `let` bindings like `ati_site`, and updates the site and prints the
+/// report however the body is left:
+///
+/// #[ati_main]
+/// fn main() { let a = 10; }
+///     -->
+/// fn main() {
+///     let mut ati = ::hand_analyze::ati::ATI::new();
+///     let mut site = ati.get_site(stringify!(main));
+///     let __ati_ret = (|| -> () {
+///         let a = 10;
+///         let a_tag = ati.tracked(stringify!(a), &a, &mut site);
+///     })();
+///     ati.update_site(site).unwrap();
+///     ati.report();
+///     __ati_ret
+/// }
+///
+/// Like with `ati_site`, the body runs in a closure, so the report is printed
+/// however it is left, and `async fn main` is not supported.
+///
+/// With `#[ati_main(debug)]`, the instrumented function is additionally
+/// rendered into a `__ATI_EXPANSION: &str` constant next to it, which can be
+/// printed to audit the injected statements.
+#[proc_macro_attribute]
+pub fn ati_main(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let item = parse_macro_input!(item as ItemFn);
+    match expand_ati_main(attr.into(), item) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn expand_ati_main(
+    attr: proc_macro2::TokenStream,
+    mut item: ItemFn,
+) -> syn::Result<proc_macro2::TokenStream> {
+    let debug = debug_flag(attr)?;
+    let name = item.sig.ident.clone();
+    instrument(
+        &mut item,
+        quote! {
+            let mut ati = ::hand_analyze::ati::ATI::new();
+            #[allow(unused_mut)]
+            let mut site = ati.get_site(stringify!(#name));
+        },
+        quote! {
+            ati.update_site(site).unwrap();
+            ati.report();
+        },
+    )?;
+    if !debug {
+        return Ok(quote!(#item));
+    }
+    let expansion = quote!(#item).to_string();
+    Ok(quote! {
+        #[allow(dead_code)]
+        const __ATI_EXPANSION: &str = #expansion;
+        #item
+    })
+}
+
+/// Parses the arguments of `ati_main`, which are either empty or `debug`.
+fn debug_flag(attr: proc_macro2::TokenStream) -> syn::Result<bool> {
+    if attr.is_empty() {
+        return Ok(false);
+    }
+    match syn::parse2::<syn::Ident>(attr.clone()) {
+        Ok(flag) if flag == "debug" => Ok(true),
+        _ => Err(syn::Error::new_spanned(
+            attr,
+            "ati_main only accepts `debug`, e.g. `#[ati_main(debug)]`",
+        )),
+    }
+}
+
+/// Parses the site id passed to `ati_site`, which must be an integer literal,
+/// into the name of the site.
+fn site_id(attr: proc_macro2::TokenStream) -> syn::Result<String> {
+    if attr.is_empty() {
+        return Err(syn::Error::new(
+            proc_macro2::Span::call_site(),
+            "ati_site expects a site id, e.g. `#[ati_site(0)]`",
+        ));
+    }
+    match syn::parse2::<Expr>(attr)? {
+        Expr::Lit(ExprLit {
+            lit: Lit::Int(id), ..
+        }) => Ok(id.base10_digits().to_owned()),
+        other => Err(syn::Error::new_spanned(
+            other,
+            "ati_site expects an integer literal as the site id",
+        )),
+    }
+}
+
+/// Tracks the simple `let` bindings of the body of `item`, and runs the body in a
+/// closure between `prelude` and `exits`, keeping the returned value. As a
+/// `return` or `?` only leaves the closure, `exits` run however the body is left.
+fn instrument(
+    item: &mut ItemFn,
+    prelude: proc_macro2::TokenStream,
+    exits: proc_macro2::TokenStream,
+) -> syn::Result<()> {
+    if let Some(asyncness) = &item.sig.asyncness {
+        return Err(syn::Error::new_spanned(
+            asyncness,
+            "async functions cannot be instrumented",
+        ));
+    }
+    TrackLets.visit_block_mut(&mut item.block);
+
+    // `impl Trait` cannot annotate a closure, its return type is inferred instead.
+    let output = match &item.sig.output {
+        ReturnType::Type(_, ty) if matches!(**ty, Type::ImplTrait(_)) => quote!(),
+        ReturnType::Type(arrow, ty) => quote!(#arrow #ty),
+        ReturnType::Default => quote!(-> ()),
+    };
+    let body = &item.block;
+    *item.block = parse_quote!({
+        #prelude
+        let __ati_ret = (|| #output #body)();
+        #exits
+        __ati_ret
+    });
+    Ok(())
+}
+
+/// Inserts a `tracked` call after every simple `let` binding, see `tracked_binding`.
+struct TrackLets;
+
+impl VisitMut for TrackLets {
+    fn visit_block_mut(&mut self, block: &mut Block) {
+        visit_mut::visit_block_mut(self, block);
+        let mut stmts = Vec::with_capacity(block.stmts.len());
+        for stmt in block.stmts.drain(..) {
+            let tracked = tracked_binding(&stmt);
+            stmts.push(stmt);
+            stmts.extend(tracked);
+        }
+        block.stmts = stmts;
+    }
+
+    // Bindings within these are not part of the body of the site.
+    fn visit_expr_closure_mut(&mut self, _: &mut ExprClosure) {}
+
+    fn visit_expr_async_mut(&mut self, _: &mut ExprAsync) {}
+
+    fn visit_item_mut(&mut self, _: &mut Item) {}
+}
+
+/// Returns the `tracked` call registering the variable bound by `stmt`, if it is
+/// a `let` binding of a single variable to a simple expression.
+fn tracked_binding(stmt: &Stmt) -> Option<Stmt> {
+    let Stmt::Local(Local {
+        pat,
+        init: Some(init),
+        ..
+    }) = stmt
+    else {
+        return None;
+    };
+    let pat = match pat {
+        Pat::Type(typed) => &*typed.pat,
+        pat => pat,
+    };
+    let ident = match pat {
+        Pat::Ident(PatIdent {
+            ident,
+            subpat: None,
+            ..
+        }) if init.diverge.is_none() => ident,
+        _ => return None,
+    };
+    if !is_simple(&init.expr) {
+        return None;
+    }
+
+    let tag = format_ident!("{}_tag", ident);
+    Some(parse_quote! {
+        #[allow(unused_variables)]
+        let #tag = ati.tracked(stringify!(#ident), &#ident, &mut site);
+    })
+}
+
+/// Whether the value of `expr` is created where it is bound, i.e. it is a literal,
+/// a variable, or arithmetic over those, rather than returned by a call.
+fn is_simple(expr: &Expr) -> bool {
+    match expr {
+        Expr::Lit(_) | Expr::Path(_) => true,
+        Expr::Binary(binary) => is_simple(&binary.left) && is_simple(&binary.right),
+        Expr::Unary(unary) => is_simple(&unary.expr),
+        Expr::Paren(paren) => is_simple(&paren.expr),
+        Expr::Cast(cast) => is_simple(&cast.expr),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ati_site_tracks_simple_lets() {
+        let item: ItemFn = parse_quote! {
+            fn f(x: u32, ati: &mut ATI) {
+                let a: u32 = 2;
+                let result = (a + x) * 2;
+                let s = format!("{result}");
+                let (p, q) = (1, 2);
+            }
+        };
+        let expected: ItemFn = parse_quote! {
+            fn f(x: u32, ati: &mut ATI) {
+                #[allow(unused_mut)]
+                let mut site = ati.get_site("0");
+                let __ati_ret = (|| -> () {
+                    let a: u32 = 2;
+                    #[allow(unused_variables)]
+                    let a_tag = ati.tracked(stringify!(a), &a, &mut site);
+                    let result = (a + x) * 2;
+                    #[allow(unused_variables)]
+                    let result_tag = ati.tracked(stringify!(result), &result, &mut site);
+                    let s = format!("{result}");
+                    let (p, q) = (1, 2);
+                })();
+                ati.update_site(site).unwrap();
+                __ati_ret
+            }
+        };
+        let expanded = expand_ati_site(quote!(0), item).unwrap();
+        assert_eq!(expanded.to_string(), quote!(#expected).to_string());
+    }
+
+    #[test]
+    fn ati_site_updates_however_the_body_is_left() {
+        let item: ItemFn = parse_quote! {
+            fn f(s: &str, ati: &mut ATI) -> Result<u32, ParseIntError> {
+                let x: u32 = s.parse()?;
+                if x > 1 {
+                    return Ok(x);
+                }
+                let call = |y: u32| -> u32 { let z = y; z };
+                Ok(call(x))
+            }
+        };
+        let expected: ItemFn = parse_quote! {
+            fn f(s: &str, ati: &mut ATI) -> Result<u32, ParseIntError> {
+                #[allow(unused_mut)]
+                let mut site = ati.get_site("0");
+                let __ati_ret = (|| -> Result<u32, ParseIntError> {
+                    let x: u32 = s.parse()?;
+                    if x > 1 {
+                        return Ok(x);
+                    }
+                    let call = |y: u32| -> u32 { let z = y; z };
+                    Ok(call(x))
+                })();
+                ati.update_site(site).unwrap();
+                __ati_ret
+            }
+        };
+        let expanded = expand_ati_site(quote!(0), item).unwrap();
+        assert_eq!(expanded.to_string(), quote!(#expected).to_string());
+    }
+
+    #[test]
+    fn ati_site_infers_impl_trait_returns() {
+        let item: ItemFn = parse_quote! {
+            fn f(ati: &mut ATI) -> impl Iterator<Item = u32> {
+                std::iter::once(1)
+            }
+        };
+        let expanded = expand_ati_site(quote!(0), item).unwrap().to_string();
+        let closure: Expr = parse_quote!((|| { std::iter::once(1) })());
+        assert!(expanded.contains(&quote!(let __ati_ret = #closure;).to_string()));
+    }
+
+    #[test]
+    fn ati_site_rejects_async_functions() {
+        let item: ItemFn = parse_quote! {
+            async fn f(ati: &mut ATI) {}
+        };
+        let err = expand_ati_site(quote!(0), item).unwrap_err();
+        assert_eq!(err.to_string(), "async functions cannot be instrumented");
+    }
+
+    #[test]
+    fn ati_site_names_the_site_by_its_id() {
+        let item: ItemFn = parse_quote!(
+            fn f(ati: &mut ATI) {}
+        );
+        let expanded = expand_ati_site(quote!(3), item).unwrap().to_string();
+        assert!(expanded.contains(&quote!(let mut site = ati.get_site("3");).to_string()));
+    }
+
+    #[test]
+    fn ati_site_rejects_missing_id() {
+        let item: ItemFn = parse_quote!(
+            fn f(ati: &mut ATI) {}
+        );
+        let err = expand_ati_site(quote!(), item).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ati_site expects a site id, e.g. `#[ati_site(0)]`"
+        );
+    }
+
+    #[test]
+    fn ati_site_rejects_non_integer_id() {
+        for attr in [quote!("main"), quote!(1.5), quote!(id)] {
+            let item: ItemFn = parse_quote!(
+                fn f(ati: &mut ATI) {}
+            );
+            let err = expand_ati_site(attr, item).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "ati_site expects an integer literal as the site id"
+            );
+        }
+    }
+
+    #[test]
+    fn ati_tagged_mirrors_data_and_inner() {
+        let inner: DeriveInput = parse_quote! {
+            struct Inner { a: u32 }
+        };
+        let expected = quote! {
+            struct InnerTag {
+                a_tag: ::hand_analyze::tag::Tag,
+            }
+
+            impl InnerTag {
+                fn new(a_tag: ::hand_analyze::tag::Tag) -> Self {
+                    InnerTag { a_tag }
+                }
+            }
+
+            impl ::hand_analyze::tag::FieldTags for InnerTag {
+                fn field_tags(&self) -> Vec<(String, &::hand_analyze::tag::Tag)> {
+                    let mut tags = Vec::new();
+                    tags.push(("a".to_owned(), &self.a_tag));
+                    tags
+                }
+            }
+        };
+        assert_eq!(
+            expand_ati_tagged(&inner).unwrap().to_string(),
+            expected.to_string()
+        );
+
+        let data: DeriveInput = parse_quote! {
+            pub struct Data { a: u32, b: String, c: Inner }
+        };
+        let expanded = expand_ati_tagged(&data).unwrap().to_string();
+        let fields = quote! {
+            pub struct DataTag {
+                pub a_tag: ::hand_analyze::tag::Tag,
+                pub b_tag: ::hand_analyze::tag::Tag,
+                pub c_tag: InnerTag,
+            }
+        };
+        assert!(expanded.contains(&fields.to_string()));
+        let new = quote! {
+            pub fn new(
+                a_tag: ::hand_analyze::tag::Tag,
+                b_tag: ::hand_analyze::tag::Tag,
+                c_tag: InnerTag
+            ) -> Self {
+                DataTag { a_tag, b_tag, c_tag }
+            }
+        };
+        assert!(expanded.contains(&new.to_string()));
+        let nested = quote! {
+            for (inner, tag) in ::hand_analyze::tag::FieldTags::field_tags(&self.c_tag) {
+                tags.push((format!("{}.{}", "c", inner), tag));
+            }
+        };
+        assert!(expanded.contains(&nested.to_string()));
+    }
+
+    #[test]
+    fn ati_tagged_rejects_tuple_structs_and_enums() {
+        let tuple: DeriveInput = parse_quote!(
+            struct Pair(u32, u32);
+        );
+        assert_eq!(
+            expand_ati_tagged(&tuple).unwrap_err().to_string(),
+            "AtiTagged only supports structs with named fields"
+        );
+        let unit: DeriveInput = parse_quote!(
+            struct Unit;
+        );
+        assert!(expand_ati_tagged(&unit).is_err());
+
+        let enumeration: DeriveInput = parse_quote!(
+            enum Either {
+                Left(u32),
+                Right(u32),
+            }
+        );
+        assert_eq!(
+            expand_ati_tagged(&enumeration).unwrap_err().to_string(),
+            "AtiTagged only supports structs"
+        );
+    }
+
+    #[test]
+    fn ati_main_reports_at_fall_through() {
+        let item: ItemFn = parse_quote! {
+            fn main() {
+                let a = 10;
+                println!("{a}");
+            }
+        };
+        let expected: ItemFn = parse_quote! {
+            fn main() {
+                let mut ati = ::hand_analyze::ati::ATI::new();
+                #[allow(unused_mut)]
+                let mut site = ati.get_site(stringify!(main));
+                let __ati_ret = (|| -> () {
+                    let a = 10;
+                    #[allow(unused_variables)]
+                    let a_tag = ati.tracked(stringify!(a), &a, &mut site);
+                    println!("{a}");
+                })();
+                ati.update_site(site).unwrap();
+                ati.report();
+                __ati_ret
+            }
+        };
+        let expanded = expand_ati_main(quote!(), item).unwrap();
+        assert_eq!(expanded.to_string(), quote!(#expected).to_string());
+    }
+
+    #[test]
+    fn ati_main_reports_after_early_exits() {
+        let item: ItemFn = parse_quote! {
+            fn main() -> Result<(), String> {
+                if std::env::args().count() > 1 {
+                    return Err("unexpected argument".to_owned());
+                }
+                let n: u32 = "4".parse().map_err(|_| "not a number")?;
+                Ok(())
+            }
+        };
+        let expected: ItemFn = parse_quote! {
+            fn main() -> Result<(), String> {
+                let mut ati = ::hand_analyze::ati::ATI::new();
+                #[allow(unused_mut)]
+                let mut site = ati.get_site(stringify!(main));
+                let __ati_ret = (|| -> Result<(), String> {
+                    if std::env::args().count() > 1 {
+                        return Err("unexpected argument".to_owned());
+                    }
+                    let n: u32 = "4".parse().map_err(|_| "not a number")?;
+                    Ok(())
+                })();
+                ati.update_site(site).unwrap();
+                ati.report();
+                __ati_ret
+            }
+        };
+        let expanded = expand_ati_main(quote!(), item).unwrap();
+        assert_eq!(expanded.to_string(), quote!(#expected).to_string());
+    }
+
+    #[test]
+    fn ati_main_debug_exposes_the_expansion() {
+        let item: ItemFn = parse_quote! {
+            fn main() {
+                let a = 10;
+            }
+        };
+        let expanded = expand_ati_main(quote!(debug), item.clone()).unwrap();
+        let file: syn::File = syn::parse2(expanded).unwrap();
+        let [Item::Const(expansion), Item::Fn(main)] = &file.items[..] else {
+            panic!("expected the expansion constant and `main`");
+        };
+        assert_eq!(expansion.ident, "__ATI_EXPANSION");
+        let Expr::Lit(ExprLit {
+            lit: Lit::Str(text),
+            ..
+        }) = &*expansion.expr
+        else {
+            panic!("expected a string literal");
+        };
+        let text = text.value();
+        assert!(text.contains("ATI :: new ()"));
+        assert!(text.contains("update_site"));
+        assert!(text.contains("report"));
+
+        let plain = expand_ati_main(quote!(), item).unwrap();
+        assert_eq!(text, plain.to_string());
+        assert_eq!(quote!(#main).to_string(), plain.to_string());
+    }
+
+    #[test]
+    fn ati_main_rejects_other_arguments() {
+        let item: ItemFn = parse_quote! {
+            fn main() {}
+        };
+        let err = expand_ati_main(quote!(verbose), item.clone()).unwrap_err();
+        assert!(err.to_string().contains("only accepts `debug`"));
+        assert!(expand_ati_main(quote!(debug, debug), item).is_err());
+    }
+}
@@ -1,48 +1,2132 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use crate::{
+    error::AtiError,
     site::{Site, Sites},
-    tag::Tag,
+    tag::{Tag, Trackable},
+    trace::TraceEvent,
     union_find::UnionFind,
 };
 
+/// Final, owned output of an analysis, as produced by `ATI::finish`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalysisResult {
+    /// Results of every analyzed site, sorted by site name.
+    pub sites: Vec<SiteResult>,
+}
+
+/// Abstract types inferred at a single site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteResult {
+    pub name: String,
+    /// Every variable at this site alongside the id of its abstract type, see
+    /// `Site::group_ids`.
+    pub vars: Vec<(String, usize)>,
+}
+
+/// Differences between the abstract types inferred by two analyses, see `ATI::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AnalysisDiff {
+    /// Every site whose variables were grouped differently, sorted by name.
+    pub sites: Vec<SiteDiff>,
+}
+
+/// Differences between the abstract types inferred at a single site. Only
+/// variables observed in both analyses are compared. Pairs are sorted, and each
+/// pair is ordered by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiteDiff {
+    pub name: String,
+    /// Pairs of variables sharing a type in the other analysis only.
+    pub merged: Vec<(String, String)>,
+    /// Pairs of variables sharing a type in this analysis only.
+    pub split: Vec<(String, String)>,
+}
+
+/// Aggregate figures of an analysis, see `ATI::stats`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtiStats {
+    /// Number of tags registered.
+    pub tags: usize,
+    /// Number of sites updated.
+    pub sites: usize,
+    /// Number of distinct abstract types globally, i.e. interaction sets among
+    /// all registered tags.
+    pub abstract_types: usize,
+    /// Number of tags in the largest abstract type.
+    pub largest_type: usize,
+}
+
+/// Kind of operation through which values interact, see `ATI::record_interaction`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InteractionKind {
+    /// e.g. `a + b`
+    Arithmetic,
+    /// e.g. `a < b`
+    Comparison,
+    /// e.g. `a = b`
+    Assignment,
+    /// e.g. `a.field`
+    FieldAccess,
+    /// Passing a value as an argument, or returning it.
+    Call,
+    Other,
+}
+
+/// Whether comparing two values makes them interact, see `ATI::record_comparison`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ComparisonPolicy {
+    /// Compared values share an abstract type, e.g. `test > limit` implies
+    /// `test` and `limit` measure the same quantity.
+    Unify,
+    /// Comparisons are recorded, but never merge abstract types.
+    #[default]
+    Ignore,
+}
+
+/// Source of the unique id of each `ATI` instance.
+static NEXT_ANALYSIS_ID: AtomicU64 = AtomicU64::new(0);
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct ATI {
-    value_uf: UnionFind,
+    value_uf: UnionFind<Tag>,
     sites: Sites,
+    id: u64,
+    tracing: bool,
+    isolation: bool,
+    deterministic_output: bool,
+    comparison_policy: ComparisonPolicy,
+    /// Every union performed, in order, if provenance recording is enabled.
+    provenance: Option<Vec<(Tag, Tag)>>,
+    /// Every interaction recorded through `record_interaction`, with its kind.
+    interactions: Vec<(InteractionKind, Vec<Tag>)>,
 }
 
 impl ATI {
     pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Creates a new analysis with room for `n` tags before reallocating, for
+    /// instrumenting large programs.
+    pub fn with_capacity(n: usize) -> Self {
         ATI {
-            value_uf: UnionFind::new(),
+            value_uf: UnionFind::with_capacity(n),
             sites: Sites::new(),
+            id: NEXT_ANALYSIS_ID.fetch_add(1, Ordering::Relaxed),
+            tracing: false,
+            isolation: false,
+            deterministic_output: true,
+            comparison_policy: ComparisonPolicy::default(),
+            provenance: None,
+            interactions: Vec::new(),
+        }
+    }
+
+    /// Makes every site returned by `get_site`/`get_site_at` isolated, as if
+    /// obtained through `get_isolated_site`. Disabled by default.
+    ///
+    /// Each isolated site clones the whole global `UnionFind`, so with isolation
+    /// enabled every `get_site` call costs time and memory proportional to the
+    /// number of tags registered so far, which adds up when a function running
+    /// many times is analyzed.
+    pub fn with_isolation(mut self, enabled: bool) -> Self {
+        self.isolation = enabled;
+        self
+    }
+
+    /// Whether reports name abstract types by their group id (e.g. `T0`, see
+    /// `group_id`), which is stable across runs of the same program, rather than
+    /// by their leader tag, whose address differs on every run. Enabled by
+    /// default.
+    pub fn with_deterministic_output(mut self, enabled: bool) -> Self {
+        self.deterministic_output = enabled;
+        self
+    }
+
+    /// Selects whether `record_comparison` merges the abstract types of the
+    /// compared values. `ComparisonPolicy::Ignore` by default.
+    pub fn with_comparison_policy(mut self, policy: ComparisonPolicy) -> Self {
+        self.comparison_policy = policy;
+        self
+    }
+
+    /// Enables logging every `tracked`, `untracked`, `union_tags` and `update_site`
+    /// call, alongside the tags and variables involved, through the `log` crate at
+    /// debug level. Useful to pin down which call caused a surprising merge.
+    /// Disabled by default, in which case nothing is formatted or logged.
+    pub fn with_tracing(mut self, enabled: bool) -> Self {
+        self.tracing = enabled;
+        self
+    }
+
+    /// Enables recording every union performed by `union_tags`/`union_all_star`,
+    /// so `why` can explain how two tags came to interact. Disabled by default, as
+    /// the log grows with every union.
+    pub fn with_provenance(mut self, enabled: bool) -> Self {
+        self.provenance = enabled.then(Vec::new);
+        self
+    }
+
+    /// Bounds the number of sites kept to `n`, evicting the least recently
+    /// updated one beyond that, see `Sites::with_capacity`. Any site updated so
+    /// far is discarded.
+    pub fn with_site_capacity(mut self, n: usize) -> Self {
+        self.sites = Sites::with_capacity(n);
+        self
+    }
+
+    /// Passes every site evicted from now on to `f`, see `with_site_capacity`.
+    pub fn on_site_evict(&mut self, f: impl FnMut(Site) + Send + 'static) {
+        self.sites.on_evict(f);
+    }
+
+    /// Discards the results of the site `name`, e.g. if it turned out to be
+    /// noise, returning whether it existed. Unions performed while analyzing it
+    /// are kept, as they are shared with every other site.
+    pub fn forget_site(&mut self, name: &str) -> bool {
+        self.sites.remove(name).is_some()
+    }
+
+    /// Forgets every tag, site and recorded union, so the next analysis starts
+    /// fresh, while retaining the allocated capacity. Settings such as tracing and
+    /// provenance recording are kept.
+    pub fn reset(&mut self) {
+        self.value_uf.clear();
+        self.sites.clear();
+        if let Some(provenance) = &mut self.provenance {
+            provenance.clear();
         }
+        self.interactions.clear();
+    }
+
+    /// Unique id of this analysis, stamped onto every tag it registers.
+    pub fn id(&self) -> u64 {
+        self.id
     }
 
     pub fn untracked<V>(&mut self, v: &V) -> Tag {
-        self.value_uf.make_set(v)
+        let (tag, _) = self
+            .value_uf
+            .introduce_tag(Tag::new(v).with_analysis(self.id));
+        if self.tracing {
+            log::debug!("untracked {tag:?}");
+        }
+        tag
     }
 
     pub fn tracked<V>(&mut self, var_name: &str, v: &V, site: &mut Site) -> Tag {
-        let tag = self.value_uf.make_set(v);
+        let (tag, _) = self
+            .value_uf
+            .introduce_tag(Tag::labeled(v, var_name).with_analysis(self.id));
+        if self.tracing {
+            log::debug!("tracked `{var_name}` at site `{}`: {tag:?}", site.name());
+        }
         site.observe_var(var_name, &tag);
         tag
     }
 
+    /// Same as `tracked`, but also captures the `Debug` representation of the
+    /// value, which reports show next to the variable, see `Site::annotate`.
+    pub fn tracked_dbg<V: Debug>(&mut self, var_name: &str, v: &V, site: &mut Site) -> Tag {
+        site.annotate(var_name, &format!("{v:?}"));
+        self.tracked(var_name, v, site)
+    }
+
+    /// Same as `tracked`, but also records the name of the type of the value, which
+    /// reports show next to the variable, e.g. `x: u32`, see `Site::annotate_type`.
+    /// The tag itself is the same as the one `tracked` creates.
+    pub fn tracked_typed<V: 'static>(&mut self, var_name: &str, v: &V, site: &mut Site) -> Tag {
+        site.annotate_type(var_name, std::any::type_name::<V>());
+        self.tracked(var_name, v, site)
+    }
+
+    /// Observes the value returned by an instrumented call, bound to `var_name`
+    /// at the caller's `site`, e.g. `m` in `let (m, m_tag) = tracked_add(...)`.
+    ///
+    /// Unlike `tracked`, no new tag is created: the returned value keeps the tag
+    /// (and therefore the interaction set) it was given within the callee, so
+    /// interactions inside the callee carry over to the caller.
+    pub fn observe_return(&mut self, site: &mut Site, var_name: &str, tag: &Tag) {
+        debug_assert!(
+            self.value_uf.find_no_compress(tag).is_some(),
+            "`{var_name}` is returned to site `{}` with an unregistered tag",
+            site.name()
+        );
+        if self.tracing {
+            log::debug!(
+                "observe_return `{var_name}` at site `{}`: {tag:?}",
+                site.name()
+            );
+        }
+        site.observe_var(var_name, tag);
+    }
+
+    /// Tracks every element of a collection separately, observing element `i` as
+    /// `base_name[i]`. Returns the tags in the same order as `items`.
+    ///
+    /// Tags are derived from each element's address at the time of this call. If
+    /// the collection reallocates or its elements are moved afterwards, the tags
+    /// keep referring to the values that were tracked here, and the moved elements
+    /// must be tracked again to obtain tags for them.
+    pub fn tracked_each<V>(&mut self, base_name: &str, items: &[V], site: &mut Site) -> Vec<Tag> {
+        items
+            .iter()
+            .enumerate()
+            .map(|(i, item)| self.tracked(&format!("{base_name}[{i}]"), item, site))
+            .collect()
+    }
+
+    /// Same as calling `tracked` on every entry in order, for values of the same
+    /// type bound to several variables at once, e.g. the fields of a wide record.
+    /// Returns the tags in the same order as `entries`.
+    pub fn tracked_many<V>(&mut self, site: &mut Site, entries: &[(&str, &V)]) -> Vec<Tag> {
+        entries
+            .iter()
+            .map(|(var_name, v)| self.tracked(var_name, *v, site))
+            .collect()
+    }
+
+    /// Tracks both elements of a tuple, e.g. the result of a call returning
+    /// `(u32, u32)`, as separate variables named by `names`. Returns the tag of
+    /// each element, in order.
+    ///
+    /// Like `tracked`, each tag is derived from the address behind the passed in
+    /// reference, so pass references to the elements themselves (e.g.
+    /// `(&pair.0, &pair.1)`) rather than to copies, which live elsewhere.
+    pub fn tracked_tuple2<A, B>(
+        &mut self,
+        names: (&str, &str),
+        vals: (&A, &B),
+        site: &mut Site,
+    ) -> (Tag, Tag) {
+        (
+            self.tracked(names.0, vals.0, site),
+            self.tracked(names.1, vals.1, site),
+        )
+    }
+
+    /// Same as `tracked_tuple2`, for tuples of three elements.
+    pub fn tracked_tuple3<A, B, C>(
+        &mut self,
+        names: (&str, &str, &str),
+        vals: (&A, &B, &C),
+        site: &mut Site,
+    ) -> (Tag, Tag, Tag) {
+        (
+            self.tracked(names.0, vals.0, site),
+            self.tracked(names.1, vals.1, site),
+            self.tracked(names.2, vals.2, site),
+        )
+    }
+
+    /// Tracks every primitive field of `value`, observing each as `base_name.path`,
+    /// e.g. `d.c.a`. Returns the tags in the order of `Trackable::tag_fields`.
+    pub fn tracked_fields<T: Trackable>(
+        &mut self,
+        base_name: &str,
+        value: &T,
+        site: &mut Site,
+    ) -> Vec<Tag> {
+        value
+            .tag_fields()
+            .into_iter()
+            .map(|(path, tag)| {
+                let var_name = format!("{base_name}.{path}");
+                let (tag, _) = self
+                    .value_uf
+                    .introduce_tag(tag.with_label(&var_name).with_analysis(self.id));
+                if self.tracing {
+                    log::debug!("tracked `{var_name}` at site `{}`: {tag:?}", site.name());
+                }
+                site.observe_var(&var_name, &tag);
+                tag
+            })
+            .collect()
+    }
+
+    /// Same as `tracked`, but the value is tagged by its content instead of its
+    /// address (see `Tag::from_hash`), so it shares a tag, and therefore an
+    /// interaction set, with every other value of equal content.
+    pub fn tracked_by_value<V: Hash>(&mut self, var_name: &str, v: &V, site: &mut Site) -> Tag {
+        let (tag, _) = self.value_uf.introduce_tag(
+            Tag::from_hash(v)
+                .with_label(var_name)
+                .with_analysis(self.id),
+        );
+        if self.tracing {
+            log::debug!(
+                "tracked `{var_name}` by value at site `{}`: {tag:?}",
+                site.name()
+            );
+        }
+        site.observe_var(var_name, &tag);
+        tag
+    }
+
+    /// Extracts the site `id` to analyze it, see `Sites::extract`. The site is
+    /// isolated if isolation is enabled, see `with_isolation`.
     pub fn get_site(&mut self, id: &str) -> Site {
-        self.sites.extract(id)
+        let site = self.sites.extract(id);
+        self.open_site(site, self.isolation)
     }
 
-    pub fn update_site(&mut self, mut site: Site) {
-        site.update(&mut self.value_uf);
+    /// Same as `get_site`, but the site is isolated: it works on its own copy of
+    /// the interaction sets, cloned from the current global ones, until it is
+    /// passed to `update_site`.
+    ///
+    /// In the default, global mode, every union affects the types inferred at
+    /// every site, since all sites share the same interaction sets. An isolated
+    /// site instead only sees the unions performed before this call, plus the
+    /// ones performed on it through `union_tags_in`, and the latter never leak
+    /// into the global interaction sets or other sites. Its variables are still
+    /// registered globally, so they can be tracked and updated as usual.
+    pub fn get_isolated_site(&mut self, id: &str) -> Site {
+        let site = self.sites.extract(id);
+        self.open_site(site, true)
+    }
+
+    /// Same as `get_site`, but for the site named `name` at the given call site,
+    /// see `Sites::extract_at`.
+    pub fn get_site_at(&mut self, name: &str, file: &str, line: u32, col: u32) -> Site {
+        let site = self.sites.extract_at(name, file, line, col);
+        self.open_site(site, self.isolation)
+    }
+
+    fn open_site(&mut self, mut site: Site, isolate: bool) -> Site {
+        site.set_analysis(self.id);
+        if isolate {
+            site.isolate(self.value_uf.clone());
+        }
+        site
+    }
+
+    /// Closes the site, folding its observations into its abstract types, and
+    /// stashes it. The site is stashed even if the update fails, so the results
+    /// of previous updates are kept.
+    pub fn update_site(&mut self, mut site: Site) -> Result<(), AtiError> {
+        let result = site.update_isolated(&mut self.value_uf);
+        if self.tracing {
+            log::debug!("update_site `{}`: {result:?}", site.name());
+        }
         self.sites.stash(site);
+        result
     }
 
-    pub fn union_tags(&mut self, tags: &[&Tag]) {
+    /// Merges the interaction sets of all passed in tags, pairwise. Fails if any
+    /// of the tags was never registered via `tracked`/`untracked`, in which case
+    /// no unions are performed.
+    pub fn union_tags(&mut self, tags: &[&Tag]) -> Result<(), AtiError> {
+        self.check_registered(tags)?;
+        if self.tracing {
+            log::debug!("union_tags {tags:?}");
+        }
         for tags in tags.windows(2) {
-            self.value_uf.union_tags(tags[0], tags[1]);
+            self.union_pair(tags[0], tags[1]);
+        }
+        Ok(())
+    }
+
+    /// Same as `union_tags`, but only performed if `pred` holds, e.g. to model
+    /// values which only interact on one branch of a condition. The predicate is
+    /// evaluated once, after checking every tag was registered, so a failing
+    /// call never evaluates it.
+    pub fn union_if(&mut self, tags: &[&Tag], pred: impl Fn() -> bool) -> Result<(), AtiError> {
+        self.check_registered(tags)?;
+        if pred() {
+            self.union_tags(tags)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Same as `union_tags`, for tags held by value, e.g. in a `Vec<Tag>`.
+    pub fn union_owned(&mut self, tags: &[Tag]) -> Result<(), AtiError> {
+        let tags: Vec<&Tag> = tags.iter().collect();
+        self.union_tags(&tags)
+    }
+
+    /// Same as `union_tags`, but additionally remembers the kind of operation
+    /// through which the values interacted, so groupings can later be derived
+    /// from certain kinds of interactions only, see `type_groups_for`.
+    pub fn record_interaction(
+        &mut self,
+        kind: InteractionKind,
+        tags: &[&Tag],
+    ) -> Result<(), AtiError> {
+        self.union_tags(tags)?;
+        self.interactions
+            .push((kind, tags.iter().map(|tag| (*tag).clone()).collect()));
+        Ok(())
+    }
+
+    /// Records that the values `a` and `b` were compared, e.g. `test > limit`.
+    /// Under `ComparisonPolicy::Unify` this is the same as `record_interaction`
+    /// with `InteractionKind::Comparison`. Under `ComparisonPolicy::Ignore` no
+    /// union is performed, but the comparison is still remembered, so that
+    /// `type_groups_for` can group by comparisons regardless of the policy.
+    /// Fails if either tag was never registered.
+    pub fn record_comparison(&mut self, a: &Tag, b: &Tag) -> Result<(), AtiError> {
+        match self.comparison_policy {
+            ComparisonPolicy::Unify => {
+                self.record_interaction(InteractionKind::Comparison, &[a, b])
+            }
+            ComparisonPolicy::Ignore => {
+                self.check_registered(&[a, b])?;
+                self.interactions
+                    .push((InteractionKind::Comparison, vec![a.clone(), b.clone()]));
+                Ok(())
+            }
         }
     }
 
+    /// Same as `union_tags`, but if `site` is isolated (see `get_isolated_site`)
+    /// the tags are only merged within its local interaction sets.
+    pub fn union_tags_in(&mut self, site: &mut Site, tags: &[&Tag]) -> Result<(), AtiError> {
+        self.check_registered(tags)?;
+        if !site.is_isolated() {
+            return self.union_tags(tags);
+        }
+        if self.tracing {
+            log::debug!("union_tags_in `{}` {tags:?}", site.name());
+        }
+        if let Some(value_uf) = site.isolated_uf(tags) {
+            for tags in tags.windows(2) {
+                value_uf.union_tags(tags[0], tags[1]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Same as `union_tags`, but merges every tag directly into the set of the
+    /// first tag rather than chaining them pairwise. The resulting set is identical,
+    /// only the internal tree is shallower, which is cheaper when many variables
+    /// interact at once.
+    pub fn union_all_star(&mut self, tags: &[&Tag]) -> Result<(), AtiError> {
+        self.check_registered(tags)?;
+        if self.tracing {
+            log::debug!("union_all_star {tags:?}");
+        }
+        if let Some((first, rest)) = tags.split_first() {
+            for tag in rest {
+                self.union_pair(first, tag);
+            }
+        }
+        Ok(())
+    }
+
+    fn union_pair(&mut self, t1: &Tag, t2: &Tag) {
+        self.value_uf.union_tags(t1, t2);
+        if let Some(provenance) = &mut self.provenance {
+            provenance.push((t1.clone(), t2.clone()));
+        }
+    }
+
+    /// Explains why the two tags share an interaction set, returning a chain of
+    /// recorded unions leading from `t1` to `t2`, each oriented along the chain.
+    /// The chain is a shortest one, and empty if both tags are the same. Returns
+    /// None if the tags are not connected, or provenance recording is disabled.
+    pub fn why(&mut self, t1: &Tag, t2: &Tag) -> Option<Vec<(Tag, Tag)>> {
+        let provenance = self.provenance.as_ref()?;
+        if !self.value_uf.connected(t1, t2)? {
+            return None;
+        }
+
+        let mut neighbours: HashMap<&Tag, Vec<&Tag>> = HashMap::new();
+        for (a, b) in provenance {
+            neighbours.entry(a).or_default().push(b);
+            neighbours.entry(b).or_default().push(a);
+        }
+
+        // Breadth first search from t1, remembering how each tag was reached.
+        let mut reached_from: HashMap<&Tag, &Tag> = HashMap::new();
+        let mut queue = VecDeque::from([t1]);
+        while let Some(tag) = queue.pop_front() {
+            if tag == t2 {
+                break;
+            }
+            for &next in neighbours.get(tag).into_iter().flatten() {
+                if next != t1 && !reached_from.contains_key(next) {
+                    reached_from.insert(next, tag);
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        let mut path = Vec::new();
+        let mut tag = t2;
+        while tag != t1 {
+            let prev = reached_from.get(tag)?;
+            path.push(((*prev).clone(), tag.clone()));
+            tag = prev;
+        }
+        path.reverse();
+        Some(path)
+    }
+
+    fn check_registered(&self, tags: &[&Tag]) -> Result<(), AtiError> {
+        match tags
+            .iter()
+            .find(|tag| self.value_uf.find_no_compress(tag).is_none())
+        {
+            Some(tag) => Err(AtiError::UnknownTag((*tag).clone())),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the other variables sharing an abstract type with `var` at the
+    /// site `site`. Fails if the site or variable is unknown.
+    pub fn peers(&mut self, site: &str, var: &str) -> Result<Vec<String>, AtiError> {
+        self.sites
+            .get_mut(site)
+            .ok_or_else(|| AtiError::UnknownSite(site.to_owned()))?
+            .peers(var)
+    }
+
+    /// Returns the variables of each abstract type at the site `site`, see
+    /// `Site::type_groups`. Fails if the site is unknown.
+    pub fn type_groups(&mut self, site: &str) -> Result<Vec<Vec<String>>, AtiError> {
+        Ok(self.site(site)?.type_groups())
+    }
+
+    /// Same as `type_groups`, but the abstract types are re-derived considering
+    /// only the interactions of the passed in kinds, see `record_interaction`.
+    /// Unions performed without a kind (e.g. through `union_tags`) are ignored.
+    pub fn type_groups_for(
+        &self,
+        site: &str,
+        kinds: &[InteractionKind],
+    ) -> Result<Vec<Vec<String>>, AtiError> {
+        let site = self.site(site)?;
+        let mut value_uf = UnionFind::new();
+        for (_, tags) in self
+            .interactions
+            .iter()
+            .filter(|(kind, _)| kinds.contains(kind))
+        {
+            for tag in tags {
+                value_uf.introduce_tag(tag.clone());
+            }
+            for tags in tags.windows(2) {
+                value_uf.union_tags(&tags[0], &tags[1]);
+            }
+        }
+        Ok(site.regroup(&mut value_uf))
+    }
+
+    fn site(&self, name: &str) -> Result<&Site, AtiError> {
+        self.sites
+            .get(name)
+            .ok_or_else(|| AtiError::UnknownSite(name.to_owned()))
+    }
+
+    /// Returns the name of every site updated so far, sorted.
+    pub fn site_names(&self) -> Vec<String> {
+        self.sites.site_names()
+    }
+
+    /// Compares the abstract types inferred by this analysis against `other`, e.g.
+    /// a run of a modified program, matching sites by name and location, and
+    /// variables by name. Sites present in only one of the analyses are ignored.
+    pub fn diff(&self, other: &ATI) -> AnalysisDiff {
+        let mut sites = Vec::new();
+        for site in self.sites.sorted() {
+            let Some(other_site) = other.sites.get_same(site) else {
+                continue;
+            };
+            let ids = site.group_ids();
+            let other_ids: HashMap<String, usize> = other_site.group_ids().into_iter().collect();
+            let shared: Vec<(&String, usize, usize)> = ids
+                .iter()
+                .filter_map(|(var, id)| Some((var, *id, *other_ids.get(var)?)))
+                .collect();
+
+            // Variables are sorted by name, so every pair already is.
+            let mut merged = Vec::new();
+            let mut split = Vec::new();
+            for (i, (var1, id1, other_id1)) in shared.iter().enumerate() {
+                for (var2, id2, other_id2) in &shared[i + 1..] {
+                    let pair = ((*var1).clone(), (*var2).clone());
+                    match (id1 == id2, other_id1 == other_id2) {
+                        (false, true) => merged.push(pair),
+                        (true, false) => split.push(pair),
+                        _ => {}
+                    }
+                }
+            }
+            if !merged.is_empty() || !split.is_empty() {
+                sites.push(SiteDiff {
+                    name: site.name().to_owned(),
+                    merged,
+                    split,
+                });
+            }
+        }
+        AnalysisDiff { sites }
+    }
+
+    /// Summarizes the analysis as a quick health check.
+    pub fn stats(&mut self) -> AtiStats {
+        let set_sizes: Vec<usize> = self
+            .value_uf
+            .leaders()
+            .iter()
+            .map(|leader| self.value_uf.set_size(leader).expect("leaders are known"))
+            .collect();
+        AtiStats {
+            tags: set_sizes.iter().sum(),
+            sites: self.sites.len(),
+            abstract_types: self.value_uf.num_sets(),
+            largest_type: set_sizes.into_iter().max().unwrap_or(0),
+        }
+    }
+
+    /// Consumes the analysis, handing out its sites as they are, e.g. to build a
+    /// custom reporter. Pending observations of sites which were never updated
+    /// are not included, as only stashed sites are kept.
+    pub fn into_sites(self) -> Sites {
+        self.sites
+    }
+
+    /// Consumes the analysis, producing its final abstract type partition.
+    pub fn finish(self) -> AnalysisResult {
+        let sites = self
+            .sites
+            .sorted()
+            .into_iter()
+            .map(|site| SiteResult {
+                name: site.name().to_owned(),
+                vars: site.group_ids(),
+            })
+            .collect();
+        AnalysisResult { sites }
+    }
+
+    /// Serializes the analysis results as JSON: an array of sites sorted by name,
+    /// each holding its `name` and a `variables` object mapping every variable
+    /// to the id of its abstract type at that site (see `Site::group_ids`).
+    pub fn to_json(&self) -> String {
+        let sites: Vec<String> = self
+            .sites
+            .sorted()
+            .into_iter()
+            .map(|site| {
+                let vars: Vec<String> = site
+                    .group_ids()
+                    .iter()
+                    .map(|(var, id)| format!("{}:{id}", json_string(var)))
+                    .collect();
+                format!(
+                    "{{\"name\":{},\"variables\":{{{}}}}}",
+                    json_string(site.name()),
+                    vars.join(",")
+                )
+            })
+            .collect();
+        format!("[{}]", sites.join(","))
+    }
+
+    /// Streams the analysis results as JSON Lines, one object per variable holding
+    /// its `site`, `variable` and `type_group_id`, without building the whole
+    /// output in memory. Sites and group ids are ordered exactly like in `to_json`.
+    pub fn write_jsonl<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        for site in self.sites.sorted() {
+            let site_name = json_string(site.name());
+            for (var, id) in site.group_ids() {
+                writeln!(
+                    w,
+                    "{{\"site\":{site_name},\"variable\":{},\"type_group_id\":{id}}}",
+                    json_string(&var)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Seeds a new analysis with the results exported by `to_json`, so that a
+    /// fresh run keeps clustering on top of them.
+    ///
+    /// The tags of the original run are not recoverable from the export, which
+    /// only records group ids. Each abstract type is therefore represented by a
+    /// synthetic tag, shared by all of its variables: the imported sites group
+    /// their variables exactly like the exported ones, and variables observed
+    /// again later are merged into these groups as usual.
+    pub fn from_json(s: &str) -> Result<ATI, AtiError> {
+        let mut ati = ATI::new();
+        for SiteResult { name, vars } in JsonReader::new(s).sites()? {
+            let mut site = ati.get_site(&name);
+            for (var, id) in vars {
+                let (tag, _) = ati.value_uf.introduce_tag(
+                    Tag::from_hash(&(&name, id))
+                        .with_label(&format!("{name}#{id}"))
+                        .with_analysis(ati.id),
+                );
+                site.observe_var(&var, &tag);
+            }
+            ati.update_site(site)?;
+        }
+        Ok(ati)
+    }
+
+    /// Replays a recorded trace against this analysis, see `TraceEvent`. Sites are
+    /// opened on their first event and stashed on `update`; sites left open at
+    /// the end of the trace are updated then. Stops at the first failing event,
+    /// e.g. a union of a value that was never tracked.
+    pub fn replay(&mut self, events: impl IntoIterator<Item = TraceEvent>) -> Result<(), AtiError> {
+        let mut open: HashMap<String, Site> = HashMap::new();
+        for event in events {
+            match event {
+                TraceEvent::Track { site, var, tag } => {
+                    let (tag, _) = self
+                        .value_uf
+                        .introduce_tag(Tag::from_raw(tag).with_label(&var).with_analysis(self.id));
+                    if self.tracing {
+                        log::debug!("replayed `{var}` at site `{site}`: {tag:?}");
+                    }
+                    open.entry(site)
+                        .or_insert_with_key(|site| self.get_site(site))
+                        .observe_var(&var, &tag);
+                }
+                TraceEvent::Union { tags } => {
+                    let tags: Vec<Tag> = tags.into_iter().map(Tag::from_raw).collect();
+                    self.union_owned(&tags)?;
+                }
+                TraceEvent::Update { site } => {
+                    let site = open.remove(&site).unwrap_or_else(|| self.get_site(&site));
+                    self.update_site(site)?;
+                }
+            }
+        }
+
+        let mut open: Vec<Site> = open.into_values().collect();
+        open.sort_by(|s1, s2| s1.name().cmp(s2.name()));
+        for site in open {
+            self.update_site(site)?;
+        }
+        Ok(())
+    }
+
+    /// Serializes the analysis results as CSV, with a `site,variable,type_group_id`
+    /// header and one row per variable. Sites and group ids are ordered exactly
+    /// like in `to_json`, so both outputs can be cross-referenced.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("site,variable,type_group_id\n");
+        for site in self.sites.sorted() {
+            for (var, id) in site.group_ids() {
+                csv.push_str(&format!(
+                    "{},{},{id}\n",
+                    csv_field(site.name()),
+                    csv_field(&var)
+                ));
+            }
+        }
+        csv
+    }
+
+    /// Renders the analysis results as a Graphviz DOT graph. Each site becomes a
+    /// cluster labeled with the site name, holding one node per variable. The
+    /// variables of each abstract type are connected as a star around the first
+    /// of them (in name order), so a type with N members produces N - 1 edges.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("graph ati {\n");
+        for (i, site) in self.sites.sorted().into_iter().enumerate() {
+            dot.push_str(&format!("    subgraph cluster_{i} {{\n"));
+            dot.push_str(&format!("        label={};\n", dot_string(site.name())));
+
+            let mut centers: HashMap<usize, String> = HashMap::new();
+            let mut edges = Vec::new();
+            for (var, id) in site.group_ids() {
+                let node = dot_string(&format!("{}::{var}", site.name()));
+                dot.push_str(&format!("        {node} [label={}];\n", dot_string(&var)));
+                match centers.get(&id) {
+                    Some(center) => edges.push(format!("        {center} -- {node};\n")),
+                    None => {
+                        centers.insert(id, node);
+                    }
+                }
+            }
+            for edge in edges {
+                dot.push_str(&edge);
+            }
+            dot.push_str("    }\n");
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Prints the abstract types of every site, naming each type by its group
+    /// id (see `group_id`), e.g. `x -> T0`.
     pub fn report(&self) {
-        self.sites.report();
+        self.report_to(&mut io::stdout())
+            .expect("failed to write report to stdout");
+    }
+
+    /// Writes the same output as `report` into the passed in writer, e.g. a
+    /// `Vec<u8>` to capture it, or a log file.
+    ///
+    /// The report opens with a summary line counting the sites, their variables
+    /// and the distinct abstract types printed for them, i.e. their group ids,
+    /// followed by every site. Variables of different sites sharing an
+    /// interaction set count as a single type, like they share a label.
+    pub fn report_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let sites = self.sites.sorted();
+        let vars: usize = sites.iter().map(|site| site.var_count()).sum();
+        let ids = self.report_ids();
+        writeln!(
+            w,
+            "{} sites, {vars} variables, {} abstract types\n",
+            sites.len(),
+            ids.len()
+        )?;
+        self.sites
+            .report_with(w, false, &|tag| self.group_label(&ids, tag))
+    }
+
+    /// Same as `report`, but omits variables which never shared an abstract type
+    /// with any other, and sites holding only such variables.
+    pub fn report_merged_only(&self) {
+        self.report_merged_only_to(&mut io::stdout())
+            .expect("failed to write merged report to stdout");
+    }
+
+    /// Writes the same output as `report_merged_only` into the passed in writer.
+    pub fn report_merged_only_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let ids = self.report_ids();
+        self.sites
+            .report_with(w, true, &|tag| self.group_label(&ids, tag))
+    }
+
+    /// Returns the small sequential id of the interaction set `tag` belongs to,
+    /// or None if `tag` was never registered.
+    ///
+    /// Ids are derived from the current interaction sets, numbering the sets
+    /// holding the abstract types of the updated sites first, in report order,
+    /// then any other set. They start at 0 and leave no gaps, so variables of
+    /// different sites sharing a set share an id. As sets merge through further
+    /// unions and updates, ids shift accordingly.
+    pub fn group_id(&mut self, tag: &Tag) -> Option<u32> {
+        let set = self.value_uf.find(tag)?;
+        let ids = self.report_ids();
+        if let Some(id) = ids.get(&set) {
+            return Some(*id);
+        }
+        let unreported = self
+            .value_uf
+            .leaders()
+            .into_iter()
+            .filter(|leader| !ids.contains_key(leader))
+            .position(|leader| leader == set)
+            .expect("the set of a registered tag is led by one of the leaders");
+        Some((ids.len() + unreported) as u32)
+    }
+
+    /// Numbers the current interaction sets holding the abstract types of every
+    /// updated site, in report order, keyed by their leader tag.
+    fn report_ids(&self) -> HashMap<Tag, u32> {
+        let mut ids = HashMap::new();
+        for site in self.sites.sorted() {
+            for leader in site.type_leaders() {
+                let set = self.value_uf.find_no_compress(&leader).unwrap_or(leader);
+                let next_id = ids.len() as u32;
+                ids.entry(set).or_insert(next_id);
+            }
+        }
+        ids
+    }
+
+    /// Names the abstract type led by `leader` at a site by the group id of its
+    /// current interaction set, e.g. `T0`, or by the tag itself without
+    /// deterministic output.
+    fn group_label(&self, ids: &HashMap<Tag, u32>, leader: &Tag) -> String {
+        let set = self
+            .value_uf
+            .find_no_compress(leader)
+            .unwrap_or_else(|| leader.clone());
+        match ids.get(&set) {
+            Some(id) if self.deterministic_output => format!("T{id}"),
+            _ => format!("{leader:?}"),
+        }
+    }
+}
+
+impl Default for ATI {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Quotes and escapes a string for use as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Minimal parser for the JSON produced by `ATI::to_json`, and for trace events,
+/// see `TraceEvent::parse`.
+pub(crate) struct JsonReader<'a> {
+    s: &'a str,
+    pos: usize,
+}
+
+impl<'a> JsonReader<'a> {
+    pub(crate) fn new(s: &'a str) -> Self {
+        JsonReader { s, pos: 0 }
+    }
+
+    /// Parses the whole input as an array of sites.
+    fn sites(mut self) -> Result<Vec<SiteResult>, AtiError> {
+        let mut sites = Vec::new();
+        self.expect('[')?;
+        if !self.eat(']') {
+            loop {
+                sites.push(self.site()?);
+                if self.eat(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        self.end()?;
+        Ok(sites)
+    }
+
+    /// Fails unless only whitespace is left.
+    pub(crate) fn end(&mut self) -> Result<(), AtiError> {
+        self.skip_whitespace();
+        if self.pos != self.s.len() {
+            return Err(self.error("trailing characters"));
+        }
+        Ok(())
+    }
+
+    fn site(&mut self) -> Result<SiteResult, AtiError> {
+        let mut name = None;
+        let mut vars = None;
+        self.expect('{')?;
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            match key.as_str() {
+                "name" => name = Some(self.string()?),
+                "variables" => vars = Some(self.variables()?),
+                _ => return Err(self.error(&format!("unexpected key `{key}`"))),
+            }
+            if self.eat('}') {
+                break;
+            }
+            self.expect(',')?;
+        }
+        match (name, vars) {
+            (Some(name), Some(vars)) => Ok(SiteResult { name, vars }),
+            _ => Err(self.error("site without `name` or `variables`")),
+        }
+    }
+
+    fn variables(&mut self) -> Result<Vec<(String, usize)>, AtiError> {
+        let mut vars = Vec::new();
+        self.expect('{')?;
+        if self.eat('}') {
+            return Ok(vars);
+        }
+        loop {
+            let var = self.string()?;
+            self.expect(':')?;
+            vars.push((var, self.number()?));
+            if self.eat('}') {
+                return Ok(vars);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, AtiError> {
+        self.expect('"')?;
+        let mut out = String::new();
+        let mut chars = self.s[self.pos..].char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok(out);
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('/') => out.push('/'),
+                    Some('n') => out.push('\n'),
+                    Some('r') => out.push('\r'),
+                    Some('t') => out.push('\t'),
+                    Some('b') => out.push('\u{8}'),
+                    Some('f') => out.push('\u{c}'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                        match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                            Some(c) => out.push(c),
+                            None => return Err(self.error("invalid unicode escape")),
+                        }
+                    }
+                    _ => return Err(self.error("invalid escape")),
+                },
+                c => out.push(c),
+            }
+        }
+        Err(self.error("unterminated string"))
+    }
+
+    pub(crate) fn number(&mut self) -> Result<usize, AtiError> {
+        self.skip_whitespace();
+        let digits = self.s[self.pos..]
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.s.len() - self.pos);
+        let number = self.s[self.pos..self.pos + digits]
+            .parse()
+            .map_err(|_| self.error("expected a number"))?;
+        self.pos += digits;
+        Ok(number)
+    }
+
+    /// Consumes `c` if it is the next non whitespace character.
+    pub(crate) fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        if self.s[self.pos..].starts_with(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    pub(crate) fn expect(&mut self, c: char) -> Result<(), AtiError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{c}`")))
+        }
+    }
+
+    pub(crate) fn skip_whitespace(&mut self) {
+        let rest = &self.s[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    pub(crate) fn error(&self, reason: &str) -> AtiError {
+        AtiError::InvalidJson(format!("{reason} at offset {}", self.pos))
+    }
+}
+
+/// Quotes a CSV field if needed, following RFC 4180.
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// Quotes and escapes a string for use as a DOT identifier.
+fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Mirror of `doubled_func` in the example binary.
+    fn doubled_func(x: u32, x_tag: &Tag, y: u32, y_tag: &Tag, ati: &mut ATI) {
+        let mut site = ati.get_site("doubled_func");
+        site.observe_var("x", x_tag);
+        site.observe_var("y", y_tag);
+
+        let a: u32 = 2;
+        let a_tag = ati.tracked("a", &a, &mut site);
+        let b: u32 = 2;
+        let b_tag = ati.tracked("b", &b, &mut site);
+
+        let result = a + x;
+        let result_tag = ati.tracked("result", &result, &mut site);
+        ati.union_tags(&[&a_tag, x_tag, &result_tag]).unwrap();
+
+        let test = b + y;
+        let test_tag = ati.tracked("test", &test, &mut site);
+        ati.union_tags(&[&b_tag, y_tag, &test_tag]).unwrap();
+
+        if test > 300 {
+            let merged = result + test;
+            ati.tracked("merged", &merged, &mut site);
+        }
+
+        ati.update_site(site).unwrap();
+    }
+
+    /// Analyzes `main` of the example binary, calling `doubled_func` twice with
+    /// tracked arguments and once with untracked ones.
+    fn analyze_doubled() -> ATI {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("main");
+        for (a, b) in [(10, 100), (20, 200)] {
+            let a_tag = ati.tracked(&format!("a{}", a / 10), &a, &mut site);
+            let b_tag = ati.tracked(&format!("b{}", a / 10), &b, &mut site);
+            doubled_func(a, &a_tag, b, &b_tag, &mut ati);
+        }
+        let (a3, b3) = (30, 300);
+        let a3_tag = ati.untracked(&a3);
+        let b3_tag = ati.untracked(&b3);
+        doubled_func(a3, &a3_tag, b3, &b3_tag, &mut ati);
+        ati.update_site(site).unwrap();
+        ati
+    }
+
+    #[test]
+    fn report_to_captures_report() {
+        let ati = analyze_doubled();
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.starts_with("2 sites, 11 variables, 5 abstract types\n"));
+        assert!(report.contains("=== doubled_func === \n"));
+        assert!(report.contains("=== main === \n"));
+        assert!(report.contains("x -> T0\n"));
+    }
+
+    #[test]
+    fn to_json_of_doubled_func() {
+        let ati = analyze_doubled();
+        assert_eq!(
+            ati.to_json(),
+            concat!(
+                r#"[{"name":"doubled_func","variables":"#,
+                r#"{"a":0,"b":1,"merged":2,"result":0,"test":1,"x":0,"y":1}},"#,
+                r#"{"name":"main","variables":{"a1":0,"a2":1,"b1":2,"b2":3}}]"#
+            )
+        );
+    }
+
+    #[test]
+    fn to_dot_draws_a_star_per_type() {
+        let ati = analyze_doubled();
+        let dot = ati.to_dot();
+        assert!(dot.starts_with("graph ati {\n"));
+        assert!(dot.contains("    subgraph cluster_0 {\n        label=\"doubled_func\";\n"));
+        assert!(dot.contains("    subgraph cluster_1 {\n        label=\"main\";\n"));
+        assert_eq!(dot.matches("[label=").count(), 11);
+        // {a, result, x} and {b, test, y} at doubled_func, nothing at main.
+        assert_eq!(dot.matches(" -- ").count(), 4);
+        assert!(dot.contains("\"doubled_func::a\" -- \"doubled_func::x\";"));
+    }
+
+    #[test]
+    fn report_is_sorted_by_site_and_variable() {
+        let mut ati = ATI::new();
+        let mut alpha = ati.get_site("alpha");
+        let c_tag = ati.tracked("c", &3, &mut alpha);
+        let b_tag = ati.tracked("b", &2, &mut alpha);
+        ati.tracked("a", &1, &mut alpha);
+        ati.union_tags(&[&b_tag, &c_tag]).unwrap();
+        let mut zeta = ati.get_site("zeta");
+        ati.tracked("z", &26, &mut zeta);
+        ati.tracked("y", &25, &mut zeta);
+        ati.update_site(alpha).unwrap();
+        ati.update_site(zeta).unwrap();
+
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2 sites, 5 variables, 4 abstract types\n\n\
+             === alpha === \na -> T0\nb -> T1\nc -> T1\n\n\n\
+             === zeta === \ny -> T2\nz -> T3\n\n\n"
+        );
+    }
+
+    #[test]
+    fn peers_of_doubled_func() {
+        let mut ati = analyze_doubled();
+        assert_eq!(ati.peers("doubled_func", "x").unwrap(), ["a", "result"]);
+        assert_eq!(ati.peers("doubled_func", "test").unwrap(), ["b", "y"]);
+        assert!(ati.peers("doubled_func", "merged").unwrap().is_empty());
+        assert!(matches!(
+            ati.peers("doubled_func", "z"),
+            Err(AtiError::UnknownVar { .. })
+        ));
+        assert!(matches!(
+            ati.peers("nowhere", "x"),
+            Err(AtiError::UnknownSite(_))
+        ));
+    }
+
+    #[test]
+    fn tracked_by_value_shares_types_of_equal_content() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let (x, y) = (42u32, 42u32);
+        ati.tracked_by_value("x", &x, &mut site);
+        ati.tracked_by_value("y", &y, &mut site);
+        ati.tracked("z", &x, &mut site);
+        ati.update_site(site).unwrap();
+        assert_eq!(ati.type_groups("f").unwrap(), [vec!["x", "y"], vec!["z"]]);
+    }
+
+    #[test]
+    fn union_tags_checks_registration() {
+        let mut ati = ATI::new();
+        assert_eq!(ati.union_tags(&[]), Ok(()));
+
+        let a = ati.untracked(&1);
+        let b = ati.untracked(&2);
+        let c = ati.untracked(&3);
+        ati.union_tags(&[&a]).unwrap();
+        assert_eq!(ati.value_uf.num_sets(), 3);
+        ati.union_tags(&[&a, &b, &c]).unwrap();
+        assert_eq!(ati.value_uf.connected(&a, &c), Some(true));
+
+        let d = ati.untracked(&4);
+        let unregistered = Tag::new(&5);
+        assert_eq!(
+            ati.union_tags(&[&d, &unregistered]),
+            Err(AtiError::UnknownTag(unregistered.clone()))
+        );
+        assert_eq!(ati.value_uf.connected(&a, &d), Some(false));
+    }
+
+    #[test]
+    fn union_all_star_is_shallower() {
+        let mut star = ATI::new();
+        let mut windowed = ATI::new();
+        let values: Vec<u32> = (0..16).collect();
+        let star_tags: Vec<Tag> = values.iter().map(|v| star.untracked(v)).collect();
+        let windowed_tags: Vec<Tag> = values.iter().map(|v| windowed.untracked(v)).collect();
+
+        star.union_all_star(&star_tags.iter().collect::<Vec<_>>())
+            .unwrap();
+        windowed
+            .union_tags(&windowed_tags.iter().collect::<Vec<_>>())
+            .unwrap();
+
+        for tag in &star_tags {
+            assert_eq!(star.value_uf.connected(&star_tags[0], tag), Some(true));
+        }
+        assert_eq!(star.value_uf.num_sets(), windowed.value_uf.num_sets());
+        let star_depth = star.value_uf.depth_stats().max_depth;
+        assert_eq!(star_depth, 1);
+        assert!(star_depth <= windowed.value_uf.depth_stats().max_depth);
+    }
+
+    #[test]
+    fn finish_of_doubled_func() {
+        let result = analyze_doubled().finish();
+        let vars = |names: &[(&str, usize)]| -> Vec<(String, usize)> {
+            names
+                .iter()
+                .map(|(var, id)| (var.to_string(), *id))
+                .collect()
+        };
+        assert_eq!(
+            result,
+            AnalysisResult {
+                sites: vec![
+                    SiteResult {
+                        name: "doubled_func".to_owned(),
+                        vars: vars(&[
+                            ("a", 0),
+                            ("b", 1),
+                            ("merged", 2),
+                            ("result", 0),
+                            ("test", 1),
+                            ("x", 0),
+                            ("y", 1),
+                        ]),
+                    },
+                    SiteResult {
+                        name: "main".to_owned(),
+                        vars: vars(&[("a1", 0), ("a2", 1), ("b1", 2), ("b2", 3)]),
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn update_site_reports_unregistered_tags() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let x = 1u32;
+        site.observe_var("x", &Tag::new(&x));
+        let err = ati.update_site(site).unwrap_err();
+        assert_eq!(
+            err,
+            AtiError::UnregisteredTag {
+                site: "f".to_owned(),
+                var: "x".to_owned(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "variable `x` observed at site `f` has a tag that was never registered, \
+             is a `tracked`/`untracked` call missing?"
+        );
+    }
+
+    #[test]
+    fn tracked_each_tags_every_element() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let items = vec![1u32, 2, 3];
+        let tags = ati.tracked_each("items", &items, &mut site);
+        assert_eq!(tags.len(), 3);
+        let x_tag = ati.tracked("x", &10u32, &mut site);
+        ati.union_tags(&[&tags[0], &x_tag]).unwrap();
+        ati.update_site(site).unwrap();
+
+        assert_eq!(
+            ati.type_groups("f").unwrap(),
+            [vec!["items[0]", "x"], vec!["items[1]"], vec!["items[2]"]]
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "with a tag from a different analysis")]
+    fn observing_tags_of_another_analysis_panics() {
+        let mut ati = ATI::new();
+        let mut other = ATI::new();
+        let foreign = other.untracked(&1);
+        let mut site = ati.get_site("f");
+        site.observe_var("x", &foreign);
+    }
+
+    #[test]
+    fn merge_partial_analyses_of_doubled_func() {
+        let run = |ati: &mut ATI, x: u32, y: u32| {
+            let x_tag = ati.untracked(&x);
+            let y_tag = ati.untracked(&y);
+            doubled_func(x, &x_tag, y, &y_tag, ati);
+        };
+        let mut first = ATI::new();
+        run(&mut first, 10, 100);
+        let mut second = ATI::new();
+        run(&mut second, 30, 300);
+        let mut other = second.get_site("doubled_func");
+        second.tracked("late", &1, &mut other);
+
+        let mut site = first.get_site("doubled_func");
+        site.merge(&other, &mut first.value_uf);
+        first.update_site(site).unwrap();
+        // Every variable of the other analysis is observed again.
+        run(&mut first, 40, 400);
+
+        assert_eq!(
+            first.type_groups("doubled_func").unwrap(),
+            [
+                vec!["a", "result", "x"],
+                vec!["b", "test", "y"],
+                vec!["merged"]
+            ]
+        );
+    }
+
+    #[test]
+    fn to_csv_of_doubled_func() {
+        let mut ati = analyze_doubled();
+        let mut site = ati.get_site("quoting");
+        ati.tracked("a,b", &1, &mut site);
+        ati.tracked("say \"hi\"", &2, &mut site);
+        ati.update_site(site).unwrap();
+
+        let csv = ati.to_csv();
+        let rows: Vec<&str> = csv.lines().collect();
+        assert_eq!(rows[0], "site,variable,type_group_id");
+        assert_eq!(
+            rows[1..8],
+            [
+                "doubled_func,a,0",
+                "doubled_func,b,1",
+                "doubled_func,merged,2",
+                "doubled_func,result,0",
+                "doubled_func,test,1",
+                "doubled_func,x,0",
+                "doubled_func,y,1",
+            ]
+        );
+        assert_eq!(
+            rows[12..],
+            ["quoting,\"a,b\",0", "quoting,\"say \"\"hi\"\"\",1"]
+        );
+    }
+
+    #[test]
+    fn type_groups_of_doubled_func() {
+        let mut ati = analyze_doubled();
+        assert_eq!(
+            ati.type_groups("doubled_func").unwrap(),
+            [
+                vec!["a", "result", "x"],
+                vec!["b", "test", "y"],
+                vec!["merged"]
+            ]
+        );
+        assert_eq!(
+            ati.type_groups("main").unwrap(),
+            [vec!["a1"], vec!["a2"], vec!["b1"], vec!["b2"]]
+        );
+        assert_eq!(
+            ati.type_groups("nowhere"),
+            Err(AtiError::UnknownSite("nowhere".to_owned()))
+        );
+    }
+
+    /// Captures every log record, see `tracing_logs_every_call`.
+    struct CaptureLogger(std::sync::Mutex<Vec<String>>);
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            self.0.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[test]
+    fn tracing_logs_every_call() {
+        static LOGGER: CaptureLogger = CaptureLogger(std::sync::Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        let mut ati = ATI::new().with_tracing(true);
+        let mut site = ati.get_site("traced");
+        let x_tag = ati.tracked("x", &1, &mut site);
+        let y_tag = ati.untracked(&2);
+        ati.union_tags(&[&x_tag, &y_tag]).unwrap();
+        ati.update_site(site).unwrap();
+
+        // Only this test enables tracing, so nothing else is logged.
+        let logs = LOGGER.0.lock().unwrap();
+        assert_eq!(logs.len(), 4);
+        assert!(logs[0].starts_with("tracked `x` at site `traced`: "));
+        assert!(logs[1].starts_with("untracked "));
+        assert!(logs[2].starts_with("union_tags "));
+        assert_eq!(logs[3], "update_site `traced`: Ok(())");
+    }
+
+    #[test]
+    fn why_explains_transitive_unions() {
+        let mut ati = ATI::new().with_provenance(true);
+        let a = ati.untracked(&1);
+        let b = ati.untracked(&2);
+        let c = ati.untracked(&3);
+        let d = ati.untracked(&4);
+        ati.union_tags(&[&a, &b]).unwrap();
+        ati.union_tags(&[&b, &c]).unwrap();
+
+        assert_eq!(
+            ati.why(&a, &c),
+            Some(vec![(a.clone(), b.clone()), (b.clone(), c.clone())])
+        );
+        assert_eq!(
+            ati.why(&c, &a),
+            Some(vec![(c.clone(), b.clone()), (b.clone(), a.clone())])
+        );
+        assert_eq!(ati.why(&a, &a), Some(vec![]));
+        assert_eq!(ati.why(&a, &d), None);
+
+        let mut unrecorded = ATI::new();
+        let a = unrecorded.untracked(&1);
+        let b = unrecorded.untracked(&2);
+        unrecorded.union_tags(&[&a, &b]).unwrap();
+        assert_eq!(unrecorded.why(&a, &b), None);
+    }
+
+    #[test]
+    fn reset_forgets_previous_analysis() {
+        let mut ati = analyze_doubled();
+        let old = ati.untracked(&1);
+        ati.reset();
+        assert!(ati.site_names().is_empty());
+        assert_eq!(ati.stats().tags, 0);
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        assert_eq!(out, b"0 sites, 0 variables, 0 abstract types\n\n");
+
+        let mut site = ati.get_site("second");
+        let x_tag = ati.tracked("x", &1, &mut site);
+        ati.update_site(site).unwrap();
+        assert_eq!(ati.site_names(), ["second"]);
+        assert_eq!(
+            ati.union_tags(&[&x_tag, &old]),
+            Err(AtiError::UnknownTag(old.clone()))
+        );
+    }
+
+    #[test]
+    fn isolated_sites_do_not_leak_unions() {
+        let mut ati = ATI::new().with_isolation(true);
+        let mut first = ati.get_site("first");
+        let mut second = ati.get_site("second");
+        let a = ati.tracked("a", &1, &mut first);
+        let b = ati.tracked("b", &2, &mut first);
+        ati.union_tags_in(&mut first, &[&a, &b]).unwrap();
+        second.observe_var("a", &a);
+        second.observe_var("b", &b);
+        ati.update_site(first).unwrap();
+        ati.update_site(second).unwrap();
+
+        assert_eq!(ati.type_groups("first").unwrap(), [vec!["a", "b"]]);
+        assert_eq!(ati.type_groups("second").unwrap(), [vec!["a"], vec!["b"]]);
+        assert_eq!(ati.value_uf.connected(&a, &b), Some(false));
+    }
+
+    #[test]
+    fn type_groups_for_filters_interaction_kinds() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let x = ati.tracked("x", &1, &mut site);
+        let y = ati.tracked("y", &2, &mut site);
+        let sum = ati.tracked("sum", &3, &mut site);
+        let limit = ati.tracked("limit", &4, &mut site);
+        ati.record_interaction(InteractionKind::Arithmetic, &[&x, &y, &sum])
+            .unwrap();
+        ati.record_interaction(InteractionKind::Comparison, &[&sum, &limit])
+            .unwrap();
+        ati.update_site(site).unwrap();
+
+        assert_eq!(
+            ati.type_groups("f").unwrap(),
+            [vec!["limit", "sum", "x", "y"]]
+        );
+        assert_eq!(
+            ati.type_groups_for("f", &[InteractionKind::Arithmetic])
+                .unwrap(),
+            [vec!["limit"], vec!["sum", "x", "y"]]
+        );
+        assert_eq!(
+            ati.type_groups_for("f", &[]).unwrap(),
+            [vec!["limit"], vec!["sum"], vec!["x"], vec!["y"]]
+        );
+    }
+
+    #[test]
+    fn report_merged_only_omits_singletons() {
+        let ati = analyze_doubled();
+        let mut out = Vec::new();
+        ati.report_merged_only_to(&mut out).unwrap();
+        // main only holds singletons, and merged is one at doubled_func.
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "=== doubled_func === \n\
+             a -> T0\nb -> T1\nresult -> T0\ntest -> T1\nx -> T0\ny -> T1\n\n\n"
+        );
+    }
+
+    #[test]
+    fn each_error_variant_is_produced_by_its_misuse() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let x = ati.tracked("x", &1, &mut site);
+        let y = ati.untracked(&2);
+        ati.update_site(site).unwrap();
+
+        let unregistered = Tag::new(&3);
+        assert_eq!(
+            ati.union_tags(&[&x, &unregistered]),
+            Err(AtiError::UnknownTag(unregistered.clone()))
+        );
+        assert_eq!(
+            ati.value_uf.rename_tag(&x, y.clone()),
+            Err(AtiError::DuplicateTag(y.clone()))
+        );
+        assert_eq!(
+            ati.type_groups("g"),
+            Err(AtiError::UnknownSite("g".to_owned()))
+        );
+        assert_eq!(
+            ati.peers("f", "z"),
+            Err(AtiError::UnknownVar {
+                site: "f".to_owned(),
+                var: "z".to_owned(),
+            })
+        );
+        assert!(matches!(
+            ATI::from_json("not json"),
+            Err(AtiError::InvalidJson(_))
+        ));
+
+        // Both a new and an old observation of x may be unknown.
+        let unregistered_x = Err(AtiError::UnregisteredTag {
+            site: "f".to_owned(),
+            var: "x".to_owned(),
+        });
+        let mut site = ati.get_site("f");
+        site.observe_var("x", &unregistered);
+        assert_eq!(ati.update_site(site), unregistered_x);
+        let mut site = ati.get_site("f");
+        site.unobserve("x");
+        site.observe_var("x", &x);
+        ati.update_site(site).unwrap();
+        ati.value_uf.remove(&x).unwrap();
+        let mut site = ati.get_site("f");
+        site.observe_var("x", &y);
+        assert_eq!(ati.update_site(site), unregistered_x);
+
+        // CrossAnalysisTag is covered by
+        // site::tests::update_rejects_tags_of_other_analyses, as observe_var
+        // asserts against it in debug builds.
+    }
+
+    #[test]
+    fn site_names_of_doubled_func() {
+        let ati = analyze_doubled();
+        assert_eq!(ati.site_names(), ["doubled_func", "main"]);
+    }
+
+    fn tracked_add(x: u32, x_tag: &Tag, y: u32, y_tag: &Tag, ati: &mut ATI) -> (u32, Tag) {
+        let mut site = ati.get_site("tracked_add");
+        site.observe_var("x", x_tag);
+        site.observe_var("y", y_tag);
+        let sum = x + y;
+        let sum_tag = ati.tracked("sum", &sum, &mut site);
+        ati.union_tags(&[x_tag, y_tag, &sum_tag]).unwrap();
+        ati.update_site(site).unwrap();
+        (sum, sum_tag)
+    }
+
+    #[test]
+    fn observe_return_carries_callee_interactions() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("main");
+        let (a, b, c) = (1u32, 2u32, 3u32);
+        let a_tag = ati.tracked("a", &a, &mut site);
+        let b_tag = ati.tracked("b", &b, &mut site);
+        ati.tracked("c", &c, &mut site);
+        let (m, m_tag) = tracked_add(a, &a_tag, b, &b_tag, &mut ati);
+        ati.observe_return(&mut site, "m", &m_tag);
+        ati.update_site(site).unwrap();
+
+        assert_eq!(m, 3);
+        assert_eq!(
+            ati.type_groups("main").unwrap(),
+            [vec!["a", "b", "m"], vec!["c"]]
+        );
+        assert_eq!(
+            ati.type_groups("tracked_add").unwrap(),
+            [vec!["sum", "x", "y"]]
+        );
+    }
+
+    #[test]
+    fn write_jsonl_writes_a_line_per_variable() {
+        let ati = analyze_doubled();
+        let mut out = Vec::new();
+        ati.write_jsonl(&mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<serde_json::Value> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let variables: usize = ati.sites.sorted().iter().map(|site| site.var_count()).sum();
+        assert_eq!(lines.len(), variables);
+        assert_eq!(variables, 11);
+        assert_eq!(
+            lines[0],
+            serde_json::json!({"site": "doubled_func", "variable": "a", "type_group_id": 0})
+        );
+        assert_eq!(
+            lines[10],
+            serde_json::json!({"site": "main", "variable": "b2", "type_group_id": 3})
+        );
+    }
+
+    #[test]
+    fn from_json_round_trips_doubled_func() {
+        let mut ati = analyze_doubled();
+        let json = ati.to_json();
+        let mut imported = ATI::from_json(&json).unwrap();
+        assert_eq!(imported.site_names(), ati.site_names());
+        for name in ati.site_names() {
+            assert_eq!(
+                imported.type_groups(&name).unwrap(),
+                ati.type_groups(&name).unwrap()
+            );
+        }
+        assert_eq!(imported.to_json(), json);
+    }
+
+    #[test]
+    fn stats_of_doubled_func() {
+        let mut ati = analyze_doubled();
+        // main tags six arguments, and each of the three calls tags a, b, result
+        // and test, the last call merged too. Every call puts x with a and result,
+        // and y with b and test, leaving merged alone.
+        assert_eq!(
+            ati.stats(),
+            AtiStats {
+                tags: 19,
+                sites: 2,
+                abstract_types: 7,
+                largest_type: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn union_owned_merges_a_vec_of_tags() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let xs = [1u32, 2, 3];
+        let tags = ati.tracked_each("xs", &xs, &mut site);
+        ati.tracked("y", &4u32, &mut site);
+        ati.union_owned(&tags).unwrap();
+        ati.update_site(site).unwrap();
+        assert_eq!(
+            ati.type_groups("f").unwrap(),
+            [vec!["xs[0]", "xs[1]", "xs[2]"], vec!["y"]]
+        );
+        assert!(matches!(
+            ati.union_owned(&[Tag::new(&())]),
+            Err(AtiError::UnknownTag(_))
+        ));
+    }
+
+    #[test]
+    fn tracked_dbg_shows_values_in_the_report() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        ati.tracked_dbg("x", &42u32, &mut site);
+        ati.tracked_dbg("s", &"hi", &mut site);
+        ati.tracked("y", &7u32, &mut site);
+        ati.update_site(site).unwrap();
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("s (=\"hi\") -> T0\n"));
+        assert!(report.contains("x (=42) -> T1\n"));
+        assert!(report.contains("y -> T2\n"));
+    }
+
+    /// Analyzes a site `f` observing `a` to `d`, where the values at `pairs`
+    /// interacted, plus a site named `only` holding `x`.
+    fn analyze_pairs(pairs: &[(usize, usize)], only: &str) -> ATI {
+        let mut ati = ATI::new();
+        let values = [1u32, 2, 3, 4];
+        let mut site = ati.get_site("f");
+        let tags: Vec<Tag> = ["a", "b", "c", "d"]
+            .iter()
+            .zip(&values)
+            .map(|(var, v)| ati.tracked(var, v, &mut site))
+            .collect();
+        for &(i, j) in pairs {
+            ati.union_tags(&[&tags[i], &tags[j]]).unwrap();
+        }
+        ati.update_site(site).unwrap();
+        let mut site = ati.get_site(only);
+        ati.tracked("x", &values[0], &mut site);
+        ati.update_site(site).unwrap();
+        ati
+    }
+
+    #[test]
+    fn diff_reports_merged_and_split_pairs() {
+        let before = analyze_pairs(&[(0, 1)], "g");
+        let after = analyze_pairs(&[(2, 3)], "h");
+        assert_eq!(
+            before.diff(&after),
+            AnalysisDiff {
+                sites: vec![SiteDiff {
+                    name: "f".to_owned(),
+                    merged: vec![("c".to_owned(), "d".to_owned())],
+                    split: vec![("a".to_owned(), "b".to_owned())],
+                }]
+            }
+        );
+        assert!(before.diff(&analyze_pairs(&[(0, 1)], "g")).sites.is_empty());
+    }
+
+    #[test]
+    fn group_ids_number_the_current_sets() {
+        let mut ati = ATI::new();
+        let (p, q, r) = (1u32, 2u32, 3u32);
+        let mut site = ati.get_site("f");
+        let p_tag = ati.tracked("p", &p, &mut site);
+        let q_tag = ati.tracked("q", &q, &mut site);
+        let r_tag = ati.tracked("r", &r, &mut site);
+        ati.update_site(site).unwrap();
+
+        ati.union_tags(&[&p_tag, &q_tag]).unwrap();
+        let mut site = ati.get_site("f");
+        site.observe_var("p", &p_tag);
+        site.observe_var("q", &q_tag);
+        ati.update_site(site).unwrap();
+        let mut site = ati.get_site("g");
+        site.observe_var("w", &q_tag);
+        ati.update_site(site).unwrap();
+        let other_tag = ati.untracked(&4u32);
+
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("p -> T0\nq -> T0\nr -> T1\n"));
+        assert!(report.contains("w -> T0\n"));
+        assert_eq!(ati.group_id(&q_tag), Some(0));
+        assert_eq!(ati.group_id(&r_tag), Some(1));
+        assert_eq!(ati.group_id(&other_tag), Some(2));
+        assert_eq!(ati.group_id(&Tag::new(&())), None);
+    }
+
+    struct Inner {
+        a: u32,
+    }
+
+    struct Data {
+        b: u32,
+        c: Inner,
+    }
+
+    impl Trackable for Inner {
+        fn tag_fields(&self) -> Vec<(String, Tag)> {
+            vec![("a".to_owned(), Tag::new(&self.a))]
+        }
+    }
+
+    impl Trackable for Data {
+        fn tag_fields(&self) -> Vec<(String, Tag)> {
+            let mut tags = vec![("b".to_owned(), Tag::new(&self.b))];
+            for (path, tag) in self.c.tag_fields() {
+                tags.push((format!("c.{path}"), tag));
+            }
+            tags
+        }
+    }
+
+    #[test]
+    fn tracked_fields_observes_nested_paths() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let d = Data {
+            b: 1,
+            c: Inner { a: 2 },
+        };
+        let x = 3u32;
+        let tags = ati.tracked_fields("d", &d, &mut site);
+        let x_tag = ati.tracked("x", &x, &mut site);
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[1].label(), Some("d.c.a"));
+        ati.union_tags(&[&tags[1], &x_tag]).unwrap();
+        ati.update_site(site).unwrap();
+        assert_eq!(
+            ati.type_groups("f").unwrap(),
+            [vec!["d.b"], vec!["d.c.a", "x"]]
+        );
+    }
+
+    #[test]
+    fn forget_site_drops_only_its_results() {
+        let mut ati = analyze_doubled();
+        assert!(ati.forget_site("doubled_func"));
+        assert!(!ati.forget_site("doubled_func"));
+        assert_eq!(ati.site_names(), ["main"]);
+        assert!(matches!(
+            ati.type_groups("doubled_func"),
+            Err(AtiError::UnknownSite(_))
+        ));
+        // The unions performed within doubled_func are kept.
+        assert_eq!(ati.stats().abstract_types, 7);
+        assert_eq!(
+            ati.type_groups("main").unwrap(),
+            [vec!["a1"], vec!["a2"], vec!["b1"], vec!["b2"]]
+        );
+    }
+
+    #[test]
+    fn union_if_only_merges_when_the_predicate_holds() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let (a, b, c) = (1u32, 2u32, 3u32);
+        let a_tag = ati.tracked("a", &a, &mut site);
+        let b_tag = ati.tracked("b", &b, &mut site);
+        let c_tag = ati.tracked("c", &c, &mut site);
+        ati.union_if(&[&a_tag, &b_tag], || a < b).unwrap();
+        ati.union_if(&[&a_tag, &c_tag], || a > c).unwrap();
+        ati.update_site(site).unwrap();
+        assert_eq!(ati.type_groups("f").unwrap(), [vec!["a", "b"], vec!["c"]]);
+
+        let unknown = Tag::from_raw(0);
+        let result = ati.union_if(&[&a_tag, &unknown], || panic!("evaluated"));
+        assert!(matches!(result, Err(AtiError::UnknownTag(_))));
+    }
+
+    #[test]
+    fn tracked_tuple2_tags_each_element() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let pair = (1u32, String::from("one"));
+        let (n_tag, s_tag) = ati.tracked_tuple2(("n", "s"), (&pair.0, &pair.1), &mut site);
+        assert_eq!(n_tag.label(), Some("n"));
+        assert_eq!(s_tag.label(), Some("s"));
+        assert_ne!(n_tag, s_tag);
+
+        let triple = (1u32, 2u32, 3u32);
+        let (a_tag, _, c_tag) = ati.tracked_tuple3(
+            ("a", "b", "c"),
+            (&triple.0, &triple.1, &triple.2),
+            &mut site,
+        );
+        ati.union_tags(&[&n_tag, &a_tag, &c_tag]).unwrap();
+        ati.update_site(site).unwrap();
+        assert_eq!(
+            ati.type_groups("f").unwrap(),
+            [vec!["a", "c", "n"], vec!["b"], vec!["s"]]
+        );
+    }
+
+    #[test]
+    fn report_opens_with_a_summary_header() {
+        let ati = analyze_doubled();
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        let (header, sites) = report.split_once("\n\n").unwrap();
+        // doubled_func has 7 variables in T0-T2, main 4 in T0, T1, T3 and T4.
+        assert_eq!(header, "2 sites, 11 variables, 5 abstract types");
+
+        let ids = ati.report_ids();
+        let mut out = Vec::new();
+        ati.sites
+            .report_with(&mut out, false, &|tag| ati.group_label(&ids, tag))
+            .unwrap();
+        assert_eq!(sites, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn report_header_counts_types_shared_by_sites_once() {
+        let mut ati = ATI::new();
+        let mut f = ati.get_site("f");
+        let x_tag = ati.tracked("x", &1, &mut f);
+        ati.tracked("z", &3, &mut f);
+        let mut g = ati.get_site("g");
+        let y_tag = ati.tracked("y", &2, &mut g);
+        ati.union_tags(&[&x_tag, &y_tag]).unwrap();
+        ati.update_site(f).unwrap();
+        ati.update_site(g).unwrap();
+
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "2 sites, 3 variables, 2 abstract types\n\n\
+             === f === \nx -> T0\nz -> T1\n\n\n\
+             === g === \ny -> T0\n\n\n"
+        );
+    }
+
+    #[test]
+    fn pairs_merged_in_one_run_stay_merged_in_later_runs() {
+        let mut ati = ATI::new();
+        for run in 0..5u32 {
+            let mut site = ati.get_site("f");
+            let (x, y, z) = (run, run + 1, run + 2);
+            let x_tag = ati.tracked("x", &x, &mut site);
+            let y_tag = ati.tracked("y", &y, &mut site);
+            ati.tracked("z", &z, &mut site);
+            if run == 0 {
+                ati.union_tags(&[&x_tag, &y_tag]).unwrap();
+            }
+            ati.update_site(site).unwrap();
+            assert_eq!(ati.type_groups("f").unwrap(), [vec!["x", "y"], vec!["z"]]);
+        }
+    }
+
+    #[test]
+    fn tracked_many_tags_entries_in_order() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let (w, h, d) = (1u32, 2u32, 3u32);
+        let tags = ati.tracked_many(&mut site, &[("w", &w), ("h", &h), ("d", &d)]);
+        let labels: Vec<Option<&str>> = tags.iter().map(Tag::label).collect();
+        assert_eq!(labels, [Some("w"), Some("h"), Some("d")]);
+        ati.union_tags(&[&tags[0], &tags[1]]).unwrap();
+        ati.update_site(site).unwrap();
+        assert_eq!(ati.type_groups("f").unwrap(), [vec!["d"], vec!["h", "w"]]);
+    }
+
+    #[test]
+    fn into_sites_hands_out_every_site_in_report_order() {
+        let ati = analyze_doubled();
+        let sites: Vec<(String, usize)> = ati
+            .into_sites()
+            .into_iter()
+            .map(|(name, site)| (name, site.var_count()))
+            .collect();
+        assert_eq!(
+            sites,
+            [("doubled_func".to_owned(), 7), ("main".to_owned(), 4)]
+        );
+    }
+
+    /// Analyzes `if test > limit { ... }` under `policy`.
+    fn analyze_comparison(policy: ComparisonPolicy) -> ATI {
+        let mut ati = ATI::new().with_comparison_policy(policy);
+        let mut site = ati.get_site("f");
+        let (test, limit) = (301u32, 300u32);
+        let test_tag = ati.tracked("test", &test, &mut site);
+        let limit_tag = ati.tracked("limit", &limit, &mut site);
+        ati.record_comparison(&test_tag, &limit_tag).unwrap();
+        ati.update_site(site).unwrap();
+        ati
+    }
+
+    #[test]
+    fn record_comparison_follows_the_policy() {
+        let mut unify = analyze_comparison(ComparisonPolicy::Unify);
+        assert_eq!(unify.type_groups("f").unwrap(), [vec!["limit", "test"]]);
+
+        let mut ignore = analyze_comparison(ComparisonPolicy::Ignore);
+        assert_eq!(
+            ignore.type_groups("f").unwrap(),
+            [vec!["limit"], vec!["test"]]
+        );
+        // The comparison is remembered regardless.
+        assert_eq!(
+            ignore
+                .type_groups_for("f", &[InteractionKind::Comparison])
+                .unwrap(),
+            [vec!["limit", "test"]]
+        );
+    }
+
+    #[test]
+    fn tracked_typed_shows_type_names_in_the_report() {
+        let mut ati = ATI::new();
+        let mut site = ati.get_site("f");
+        let n = 1u32;
+        let s = String::from("one");
+        let n_tag = ati.tracked_typed("n", &n, &mut site);
+        ati.tracked_typed("s", &s, &mut site);
+        assert_eq!(n_tag.label(), Some("n"));
+        ati.update_site(site).unwrap();
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("n: u32 -> T0\n"));
+        assert!(report.contains("s: alloc::string::String -> T1\n"));
     }
 }
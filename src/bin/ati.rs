@@ -0,0 +1,42 @@
+use std::io::{self, BufRead};
+use std::process;
+
+use hand_analyze::{ati::ATI, trace::TraceEvent};
+
+/// Replays a trace read from stdin, one JSON event per line (see `TraceEvent`),
+/// and prints the report, or the JSON export when passed `--json`, e.g.
+/// `cargo run --bin ati -- --json < trace.jsonl`.
+fn main() {
+    let json = match std::env::args().nth(1).as_deref() {
+        None => false,
+        Some("--json") => true,
+        Some(arg) => fail(&format!("unexpected argument `{arg}`, usage: ati [--json] < trace")),
+    };
+
+    let mut events = Vec::new();
+    for (i, line) in io::stdin().lock().lines().enumerate() {
+        let line = line.unwrap_or_else(|e| fail(&format!("failed to read stdin: {e}")));
+        if line.trim().is_empty() {
+            continue;
+        }
+        match TraceEvent::parse(&line) {
+            Ok(event) => events.push(event),
+            Err(e) => fail(&format!("line {}: {e}", i + 1)),
+        }
+    }
+
+    let mut ati = ATI::new();
+    if let Err(e) = ati.replay(events) {
+        fail(&e.to_string());
+    }
+    if json {
+        println!("{}", ati.to_json());
+    } else {
+        ati.report();
+    }
+}
+
+fn fail(message: &str) -> ! {
+    eprintln!("ati: {message}");
+    process::exit(1)
+}
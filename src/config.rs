@@ -0,0 +1,128 @@
+use crate::ati::{ATI, ComparisonPolicy};
+
+/// Settings of an `ATI`, to enable several optional features at once without a
+/// constructor per combination, e.g.
+/// `AtiConfig::builder().tracing(true).capacity(1 << 20).build()`.
+///
+/// Every setting defaults to the behavior of `ATI::new`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AtiConfig {
+    capacity: usize,
+    tracing: bool,
+    provenance: bool,
+    isolation: bool,
+    deterministic_output: bool,
+    comparison_policy: ComparisonPolicy,
+}
+
+impl AtiConfig {
+    pub fn builder() -> Self {
+        AtiConfig {
+            capacity: 0,
+            tracing: false,
+            provenance: false,
+            isolation: false,
+            deterministic_output: true,
+            comparison_policy: ComparisonPolicy::Ignore,
+        }
+    }
+
+    /// Number of tags to allocate room for up front, see `ATI::with_capacity`.
+    pub fn capacity(mut self, n: usize) -> Self {
+        self.capacity = n;
+        self
+    }
+
+    /// See `ATI::with_tracing`.
+    pub fn tracing(mut self, enabled: bool) -> Self {
+        self.tracing = enabled;
+        self
+    }
+
+    /// See `ATI::with_provenance`.
+    pub fn provenance(mut self, enabled: bool) -> Self {
+        self.provenance = enabled;
+        self
+    }
+
+    /// See `ATI::with_isolation`.
+    pub fn isolation(mut self, enabled: bool) -> Self {
+        self.isolation = enabled;
+        self
+    }
+
+    /// See `ATI::with_deterministic_output`.
+    pub fn deterministic_output(mut self, enabled: bool) -> Self {
+        self.deterministic_output = enabled;
+        self
+    }
+
+    /// See `ATI::with_comparison_policy`.
+    pub fn comparison_policy(mut self, policy: ComparisonPolicy) -> Self {
+        self.comparison_policy = policy;
+        self
+    }
+
+    /// Creates a new analysis with these settings.
+    pub fn build(&self) -> ATI {
+        ATI::with_capacity(self.capacity)
+            .with_tracing(self.tracing)
+            .with_provenance(self.provenance)
+            .with_isolation(self.isolation)
+            .with_deterministic_output(self.deterministic_output)
+            .with_comparison_policy(self.comparison_policy)
+    }
+}
+
+impl Default for AtiConfig {
+    fn default() -> Self {
+        Self::builder()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tag::Tag;
+
+    /// Tracks `a` and `b` at site `f`, compares them, and returns the tags.
+    fn compare(ati: &mut ATI) -> (Tag, Tag) {
+        let mut site = ati.get_site("f");
+        let a_tag = ati.tracked("a", &1u32, &mut site);
+        let b_tag = ati.tracked("b", &2u32, &mut site);
+        ati.record_comparison(&a_tag, &b_tag).unwrap();
+        ati.update_site(site).unwrap();
+        (a_tag, b_tag)
+    }
+
+    #[test]
+    fn default_behaves_like_new() {
+        let mut ati = AtiConfig::default().build();
+        assert!(!ati.get_site("g").is_isolated());
+        let (a_tag, b_tag) = compare(&mut ati);
+        assert_eq!(ati.type_groups("f").unwrap(), [vec!["a"], vec!["b"]]);
+        assert_eq!(ati.why(&a_tag, &b_tag), None);
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("a -> T0\n"));
+    }
+
+    #[test]
+    fn build_applies_every_setting() {
+        let mut ati = AtiConfig::builder()
+            .capacity(16)
+            .provenance(true)
+            .comparison_policy(ComparisonPolicy::Unify)
+            .deterministic_output(false)
+            .build();
+        let (a_tag, b_tag) = compare(&mut ati);
+        assert_eq!(ati.type_groups("f").unwrap(), [vec!["a", "b"]]);
+        assert_eq!(ati.why(&a_tag, &b_tag).map(|chain| chain.len()), Some(1));
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        assert!(!String::from_utf8(out).unwrap().contains("T0"));
+
+        let mut ati = AtiConfig::builder().isolation(true).build();
+        assert!(ati.get_site("g").is_isolated());
+    }
+}
@@ -0,0 +1,52 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::tag::Tag;
+
+/// Errors surfaced by the analysis when it is driven incorrectly, typically due to
+/// a bug in the instrumentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AtiError {
+    /// A tag was used which was never introduced via `tracked`/`untracked`.
+    UnknownTag(Tag),
+    /// A tag was introduced which already identifies another element.
+    DuplicateTag(Tag),
+    /// A variable was observed at a site with a tag that was never introduced
+    /// via `tracked`/`untracked`.
+    UnregisteredTag { site: String, var: String },
+    /// A site was queried which was never updated.
+    UnknownSite(String),
+    /// A variable was queried which was never observed at the site.
+    UnknownVar { site: String, var: String },
+    /// Input passed to `ATI::from_json` is not in the format of `ATI::to_json`,
+    /// or a trace event is malformed, see `TraceEvent::parse`.
+    InvalidJson(String),
+    /// A variable was observed at a site with a tag registered by a different
+    /// `ATI` instance than the one owning the site.
+    CrossAnalysisTag { site: String, var: String },
+}
+
+impl fmt::Display for AtiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AtiError::UnknownTag(tag) => write!(f, "tag {tag:?} was never registered"),
+            AtiError::DuplicateTag(tag) => write!(f, "tag {tag:?} is already registered"),
+            AtiError::UnregisteredTag { site, var } => write!(
+                f,
+                "variable `{var}` observed at site `{site}` has a tag that was never registered, \
+                 is a `tracked`/`untracked` call missing?"
+            ),
+            AtiError::UnknownSite(site) => write!(f, "site `{site}` was never updated"),
+            AtiError::UnknownVar { site, var } => {
+                write!(f, "variable `{var}` was never observed at site `{site}`")
+            }
+            AtiError::InvalidJson(reason) => write!(f, "invalid JSON input: {reason}"),
+            AtiError::CrossAnalysisTag { site, var } => write!(
+                f,
+                "variable `{var}` observed at site `{site}` has a tag from a different analysis"
+            ),
+        }
+    }
+}
+
+impl Error for AtiError {}
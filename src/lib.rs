@@ -0,0 +1,12 @@
+pub mod ati;
+pub mod config;
+pub mod error;
+mod macros;
+pub mod metrics;
+pub mod shared;
+pub mod site;
+pub mod tag;
+pub mod trace;
+pub mod union_find;
+
+pub use hand_analyze_macros::{AtiTagged, ati_main, ati_site};
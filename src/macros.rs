@@ -0,0 +1,54 @@
+/// Tracks the variable `x` at `site`, naming it after the variable itself:
+/// `ati_track!(ati, site, x)` expands to `ati.tracked(stringify!(x), &x, &mut site)`,
+/// evaluating to the tag of `x`.
+#[macro_export]
+macro_rules! ati_track {
+    ($ati:expr, $site:expr, $var:ident) => {
+        $ati.tracked(stringify!($var), &$var, &mut $site)
+    };
+}
+
+/// Records that the values of the passed in tags interacted:
+/// `ati_interact!(ati, a_tag, b_tag)` expands to `ati.union_tags(&[&a_tag, &b_tag])`.
+#[macro_export]
+macro_rules! ati_interact {
+    ($ati:expr, $($tag:expr),+ $(,)?) => {
+        $ati.union_tags(&[$(&$tag),+])
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ati::ATI;
+
+    #[test]
+    fn expansions_match_hand_written_calls() {
+        let (a, x, y) = (2u32, 10u32, 20u32);
+
+        let mut hand = ATI::new();
+        let mut site = hand.get_site("f");
+        let a_tag = hand.tracked(stringify!(a), &a, &mut site);
+        let x_tag = hand.tracked(stringify!(x), &x, &mut site);
+        let result = a + x;
+        let result_tag = hand.tracked(stringify!(result), &result, &mut site);
+        hand.union_tags(&[&a_tag, &x_tag, &result_tag]).unwrap();
+        hand.tracked(stringify!(y), &y, &mut site);
+        hand.update_site(site).unwrap();
+
+        let mut macros = ATI::new();
+        let mut site = macros.get_site("f");
+        let a_tag = ati_track!(macros, site, a);
+        let x_tag = ati_track!(macros, site, x);
+        let result_tag = ati_track!(macros, site, result);
+        ati_interact!(macros, a_tag, x_tag, result_tag).unwrap();
+        ati_track!(macros, site, y);
+        macros.update_site(site).unwrap();
+
+        assert_eq!(
+            macros.type_groups("f").unwrap(),
+            [vec!["a", "result", "x"], vec!["y"]]
+        );
+        assert_eq!(macros.type_groups("f"), hand.type_groups("f"));
+        assert_eq!(macros.to_json(), hand.to_json());
+    }
+}
@@ -1,10 +1,8 @@
-mod ati;
-mod site;
-mod tag;
-mod union_find;
-
-use ati::ATI;
-use tag::Tag;
+use hand_analyze::{
+    AtiTagged,
+    ati::ATI,
+    tag::{Tag, Trackable},
+};
 
 // TODO: Create a Tag trait, that way we can treat Struct tags in the exact same way we treat value tags
 
@@ -174,7 +172,7 @@ fn main() {
 
     uses_structs(&mut ati);
 
-    ati.update_site(site);
+    ati.update_site(site).unwrap();
     ati.report()
 }
 
@@ -196,11 +194,11 @@ fn doubled_func(x: u32, x_tag: &Tag, y: u32, y_tag: &Tag, ati: &mut ATI) {
 
     let result: u32 = a + x;
     let result_tag: Tag = ati.tracked(stringify!(result), &result, &mut site);
-    ati.union_tags(&[&a_tag, &x_tag, &result_tag]);
+    ati.union_tags(&[&a_tag, x_tag, &result_tag]).unwrap();
 
     let test: u32 = b + y;
     let test_tag: Tag = ati.tracked(stringify!(test), &test, &mut site);
-    ati.union_tags(&[&b_tag, &y_tag, &test_tag]);
+    ati.union_tags(&[&b_tag, y_tag, &test_tag]).unwrap();
 
     if test > 300 {
         /*
@@ -220,7 +218,7 @@ fn doubled_func(x: u32, x_tag: &Tag, y: u32, y_tag: &Tag, ati: &mut ATI) {
             "created" in this scope, and therefore tracked.
         */
         let merged = untracked_add(result, test);
-        let merged_tag: Tag = ati.tracked(stringify!(merged), &merged, &mut site);
+        let _merged_tag: Tag = ati.tracked(stringify!(merged), &merged, &mut site);
 
         /*
             tracked_add() is a function we do instrument.
@@ -232,7 +230,7 @@ fn doubled_func(x: u32, x_tag: &Tag, y: u32, y_tag: &Tag, ati: &mut ATI) {
         // site.observe_var(stringify!(merged), &merged_tag);
     }
 
-    ati.update_site(site);
+    ati.update_site(site).unwrap();
 }
 
 // returns the n-th fib number (0-indexed) and 2^n
@@ -252,30 +250,30 @@ fn complex_func(iterations: u32, iterations_tag: &Tag, ati: &mut ATI) -> (u32, u
 
     for i in 0..iterations {
         let i_tag = ati.tracked(stringify!(i), &i, &mut site);
-        ati.union_tags(&[&i_tag, &iterations_tag]);
+        ati.union_tags(&[&i_tag, iterations_tag]).unwrap();
 
         let tmp = next;
         let tmp_tag = ati.tracked(stringify!(tmp), &tmp, &mut site);
-        ati.union_tags(&[&tmp_tag, &next_tag]);
+        ati.union_tags(&[&tmp_tag, &next_tag]).unwrap();
 
         // TODO: with SSA, this problem goes away, where an old tag has to be merged before the statement
-        ati.union_tags(&[&current_tag, &next_tag]);
-        next = current + next;
+        ati.union_tags(&[&current_tag, &next_tag]).unwrap();
+        next += current;
         let next_tag = ati.tracked(stringify!(next), &next, &mut site);
-        ati.union_tags(&[&next_tag, &current_tag]);
+        ati.union_tags(&[&next_tag, &current_tag]).unwrap();
 
         current = tmp;
         let current_tag = ati.tracked(stringify!(current), &current, &mut site);
-        ati.union_tags(&[&current_tag, &tmp_tag]);
+        ati.union_tags(&[&current_tag, &tmp_tag]).unwrap();
 
         // TODO: same sort of thing here, awkward tag management due to no SSA
         let old_tag = pows_of_two_tag.clone();
         pows_of_two = pows_of_two + pows_of_two;
         let pows_of_two_tag = ati.tracked(stringify!(pows_of_two), &pows_of_two, &mut site);
-        ati.union_tags(&[&pows_of_two_tag, &old_tag])
+        ati.union_tags(&[&pows_of_two_tag, &old_tag]).unwrap();
     }
 
-    ati.update_site(site);
+    ati.update_site(site).unwrap();
 
     (current, pows_of_two)
 }
@@ -283,11 +281,11 @@ fn complex_func(iterations: u32, iterations_tag: &Tag, ati: &mut ATI) -> (u32, u
 /// a "library function", which is not instrumented for ATI
 // MARK: TRACKED/UNTRACKED
 fn untracked_add(a: u32, b: u32) -> u32 {
-    let res = a + b;
-    res
+    a + b
 }
 
 /// a function instrumented for ATI
+#[allow(dead_code)]
 fn tracked_add(a: u32, a_tag: &Tag, b: u32, b_tag: &Tag, ati: &mut ATI) -> (u32, Tag) {
     let mut site = ati.get_site(stringify!(tracked_add));
     site.observe_var(stringify!(a), a_tag);
@@ -295,9 +293,9 @@ fn tracked_add(a: u32, a_tag: &Tag, b: u32, b_tag: &Tag, ati: &mut ATI) -> (u32,
 
     let res = a + b;
     let res_tag = ati.tracked(stringify!(res), &res, &mut site);
-    ati.union_tags(&[&a_tag, &b_tag, &res_tag]);
+    ati.union_tags(&[a_tag, b_tag, &res_tag]).unwrap();
 
-    ati.update_site(site);
+    ati.update_site(site).unwrap();
 
     // NOTE: all cross-function boundary values need to also pass tags
     // which includes not just the parameters, but returns as well.
@@ -305,24 +303,38 @@ fn tracked_add(a: u32, a_tag: &Tag, b: u32, b_tag: &Tag, ati: &mut ATI) -> (u32,
 }
 
 // MARK: STRUCT HANDLING
+// `AtiTagged` generates the `DataTag` / `InnerTag` companion structs
+#[allow(dead_code)]
+#[derive(AtiTagged)]
 struct Data {
     a: u32,
     b: String,
     c: Inner,
 }
 
-struct DataTag {
-    a_tag: Tag,
-    b_tag: Tag,
-    c_tag: InnerTag,
-}
-
+#[derive(AtiTagged)]
 struct Inner {
     a: u32,
 }
 
-struct InnerTag {
-    a_tag: Tag,
+// `Trackable` lets `ati.tracked_fields` tag every nested field without a `DataTag`
+impl Trackable for Data {
+    fn tag_fields(&self) -> Vec<(String, Tag)> {
+        let mut tags = vec![
+            (stringify!(a).to_owned(), Tag::new(&self.a)),
+            (stringify!(b).to_owned(), Tag::new(&self.b)),
+        ];
+        for (path, tag) in self.c.tag_fields() {
+            tags.push((format!("{}.{path}", stringify!(c)), tag));
+        }
+        tags
+    }
+}
+
+impl Trackable for Inner {
+    fn tag_fields(&self) -> Vec<(String, Tag)> {
+        vec![(stringify!(a).to_owned(), Tag::new(&self.a))]
+    }
 }
 
 impl Data {
@@ -338,44 +350,33 @@ impl Data {
         let inner_a_tag = ati.tracked(stringify!(Inner::a), &inner_a, &mut site);
 
         let inner = Inner { a: inner_a };
-        let inner_tag = InnerTag { a_tag: inner_a_tag };
-
-        ati.update_site(site);
-
-        (
-            Data { a, b, c: inner },
-            DataTag {
-                a_tag,
-                b_tag,
-                c_tag: inner_tag,
-            },
-        )
+        let inner_tag = InnerTag::new(inner_a_tag);
+
+        ati.update_site(site).unwrap();
+
+        (Data { a, b, c: inner }, DataTag::new(a_tag, b_tag, inner_tag))
     }
 }
 
 fn accepts_struct_add_fields(data: &mut Data, data_tag: &mut DataTag, ati: &mut ATI) {
     let mut site = ati.get_site(stringify!(accepts_struct_add_fields));
 
-    site.observe_var(stringify!(data.a), &data_tag.a_tag);
-    site.observe_var(stringify!(data.b), &data_tag.b_tag);
-    site.observe_var(stringify!(data.c.a), &data_tag.c_tag.a_tag);
+    site.observe_struct(stringify!(data), data_tag);
 
     data.c.a += data.a;
-    ati.union_tags(&[&data_tag.a_tag, &data_tag.c_tag.a_tag]);
+    ati.union_tags(&[&data_tag.a_tag, &data_tag.c_tag.a_tag]).unwrap();
 
-    ati.update_site(site);
+    ati.update_site(site).unwrap();
 }
 
 fn uses_structs(ati: &mut ATI) {
     let mut site = ati.get_site(stringify!(uses_structs));
 
     let (mut d, mut d_tag) = Data::new(ati);
-    site.observe_var(stringify!(d.a), &d_tag.a_tag);
-    site.observe_var(stringify!(d.b), &d_tag.b_tag);
-    site.observe_var(stringify!(d.c.a), &d_tag.c_tag.a_tag);
+    site.observe_struct(stringify!(d), &d_tag);
 
 
     accepts_struct_add_fields(&mut d, &mut d_tag, ati);
 
-    ati.update_site(site);
+    ati.update_site(site).unwrap();
 }
@@ -0,0 +1,87 @@
+use std::collections::{BTreeSet, HashMap};
+
+/// Computes the Rand index between two partitions of variable names, e.g. the
+/// abstract types inferred by ATI and a hand-labeled ground truth.
+///
+/// The result is the fraction of variable pairs on which both partitions agree,
+/// either by placing both variables in the same group, or by separating them.
+/// 1.0 means the partitions are identical. A variable missing from one of the
+/// partitions is treated as a singleton group there.
+pub fn rand_index(result: &[Vec<String>], truth: &[Vec<String>]) -> f64 {
+    let result_groups = group_of(result);
+    let truth_groups = group_of(truth);
+
+    let vars: Vec<&str> = result_groups
+        .keys()
+        .chain(truth_groups.keys())
+        .copied()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    let mut pairs = 0;
+    let mut agreements = 0;
+    for (i, v1) in vars.iter().enumerate() {
+        for v2 in &vars[i + 1..] {
+            pairs += 1;
+            if same_group(&result_groups, v1, v2) == same_group(&truth_groups, v1, v2) {
+                agreements += 1;
+            }
+        }
+    }
+
+    if pairs == 0 {
+        return 1.0;
+    }
+    agreements as f64 / pairs as f64
+}
+
+/// Maps every variable of the partition to the index of its group.
+fn group_of(partition: &[Vec<String>]) -> HashMap<&str, usize> {
+    partition
+        .iter()
+        .enumerate()
+        .flat_map(|(i, group)| group.iter().map(move |var| (var.as_str(), i)))
+        .collect()
+}
+
+/// Whether both variables belong to the same group. Missing variables are
+/// singletons, so they never share a group.
+fn same_group(groups: &HashMap<&str, usize>, v1: &str, v2: &str) -> bool {
+    match (groups.get(v1), groups.get(v2)) {
+        (Some(g1), Some(g2)) => g1 == g2,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partition(groups: &[&[&str]]) -> Vec<Vec<String>> {
+        groups
+            .iter()
+            .map(|group| group.iter().map(|var| var.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn identical_partitions() {
+        let result = partition(&[&["a", "result", "x"], &["b", "y"]]);
+        let truth = partition(&[&["y", "b"], &["x", "a", "result"]]);
+        assert_eq!(rand_index(&result, &truth), 1.0);
+        assert_eq!(rand_index(&[], &[]), 1.0);
+    }
+
+    #[test]
+    fn partial_overlap() {
+        // Of the 6 pairs, only (a, c) and (b, c) are grouped differently.
+        let result = partition(&[&["a", "b"], &["c"], &["d"]]);
+        let truth = partition(&[&["a", "b", "c"], &["d"]]);
+        assert_eq!(rand_index(&result, &truth), 4.0 / 6.0);
+
+        // d is missing from the result, so it is a singleton there.
+        let result = partition(&[&["a", "b", "c"]]);
+        assert_eq!(rand_index(&result, &truth), 1.0);
+    }
+}
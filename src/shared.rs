@@ -0,0 +1,109 @@
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::{ati::ATI, error::AtiError, site::Site, tag::Tag};
+
+/// Thread-safe wrapper around `ATI`, for instrumenting programs which run analyzed
+/// functions concurrently. Every method takes `&self`, so a `SharedAti` can be
+/// placed in a `static` or shared behind an `Arc`.
+///
+/// Each call locks the whole analysis for its duration, so extracting and
+/// stashing a site is atomic with respect to other threads. A `Site` is owned by
+/// the thread between `get_site` and `update_site`, so two threads analyzing
+/// different sites never observe each other's partial state. Two threads
+/// analyzing the same site at once each receive their own `Site`, and the one
+/// updated last replaces the other when stashed.
+pub struct SharedAti {
+    inner: Mutex<ATI>,
+}
+
+impl SharedAti {
+    pub fn new() -> Self {
+        SharedAti {
+            inner: Mutex::new(ATI::new()),
+        }
+    }
+
+    pub fn untracked<V>(&self, v: &V) -> Tag {
+        self.lock().untracked(v)
+    }
+
+    pub fn tracked<V>(&self, var_name: &str, v: &V, site: &mut Site) -> Tag {
+        self.lock().tracked(var_name, v, site)
+    }
+
+    pub fn get_site(&self, id: &str) -> Site {
+        self.lock().get_site(id)
+    }
+
+    pub fn update_site(&self, site: Site) -> Result<(), AtiError> {
+        self.lock().update_site(site)
+    }
+
+    pub fn union_tags(&self, tags: &[&Tag]) -> Result<(), AtiError> {
+        self.lock().union_tags(tags)
+    }
+
+    pub fn report(&self) {
+        self.lock().report();
+    }
+
+    pub fn report_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.lock().report_to(w)
+    }
+
+    /// Consumes the wrapper, returning the underlying analysis.
+    pub fn into_inner(self) -> ATI {
+        self.inner.into_inner().expect("ATI mutex poisoned")
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ATI> {
+        self.inner.lock().expect("ATI mutex poisoned")
+    }
+}
+
+impl Default for SharedAti {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Instrumented `fn f(x) { let y = x + 1; }`, analyzed as the site `name`.
+    fn instrumented(ati: &SharedAti, name: &str) {
+        let mut site = ati.get_site(name);
+        for x in 0..100u32 {
+            let x_tag = ati.tracked("x", &x, &mut site);
+            let y = x + 1;
+            let y_tag = ati.tracked("y", &y, &mut site);
+            ati.union_tags(&[&x_tag, &y_tag]).unwrap();
+            ati.untracked(&y);
+        }
+        ati.update_site(site).unwrap();
+    }
+
+    #[test]
+    fn threads_analyze_sites_concurrently() {
+        let ati = SharedAti::new();
+        std::thread::scope(|scope| {
+            scope.spawn(|| instrumented(&ati, "left"));
+            scope.spawn(|| instrumented(&ati, "right"));
+        });
+
+        let mut out = Vec::new();
+        ati.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.starts_with("2 sites, 4 variables, 2 abstract types\n"));
+
+        let mut ati = ati.into_inner();
+        assert_eq!(ati.site_names(), ["left", "right"]);
+        for site in ["left", "right"] {
+            assert_eq!(ati.type_groups(site).unwrap(), [vec!["x", "y"]]);
+        }
+        // 100 (x, y) pairs and 100 untracked values per thread.
+        assert_eq!(ati.stats().tags, 600);
+    }
+}
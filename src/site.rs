@@ -1,6 +1,10 @@
-use std::collections::HashMap;
+#[cfg(feature = "ordered")]
+use std::collections::BTreeMap;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Write};
 
-use crate::Tag;
+use crate::error::AtiError;
+use crate::tag::{FieldTags, Tag};
 use crate::union_find::UnionFind;
 
 /// A site captures a set of lines in the source code under analysis. A site starts
@@ -22,84 +26,1166 @@ use crate::union_find::UnionFind;
 ///
 /// `var_tags` contains the ATI output, mapping the variable identifiers (names) to a value tag,
 /// the leader tag of a set of values in `value_uf` which have been observed interacting together.
+///
+/// A site may additionally carry the `(file, line, col)` location of the call site,
+/// distinguishing sites which share a name (e.g. the same function called from
+/// different places).
 pub struct Site {
-    type_uf: UnionFind,
-    var_tags: HashMap<String, Tag>,
+    type_uf: UnionFind<Tag>,
+    var_tags: VarMap,
     observed_var_tags: Vec<(String, Tag)>,
+    /// Every observation ever made at this site, see `regroup`.
+    history: Vec<(String, Tag)>,
+    /// Debug representation of the last value of a variable, see `annotate`.
+    values: HashMap<String, String>,
+    /// Name of the type of a variable, see `annotate_type`.
+    types: HashMap<String, String>,
     name: String, // Debug information
+    location: Option<Location>,
+    analysis: Option<u64>,
+    /// Site-local copy of the interaction sets, while the site is isolated.
+    isolated_uf: Option<UnionFind<Tag>>,
+    /// Whether a variable only takes the type of its latest observation.
+    latest_only: bool,
 }
 
+/// `(file, line, col)` of a call site.
+pub type Location = (String, u32, u32);
+
+/// Maps the variables of a site to their tags. With the `ordered` feature this is
+/// a `BTreeMap`, so variables are iterated in name order without sorting at
+/// report time; otherwise a faster `HashMap`.
+#[cfg(feature = "ordered")]
+pub type VarMap = BTreeMap<String, Tag>;
+#[cfg(not(feature = "ordered"))]
+pub type VarMap = HashMap<String, Tag>;
+
+/// Identifies a site within `Sites`: its name and, if any, its location.
+type SiteKey = (String, Option<Location>);
+
 impl Site {
     pub fn new(name: &str) -> Self {
         Site {
             type_uf: UnionFind::new(),
-            var_tags: HashMap::new(),
+            var_tags: VarMap::new(),
             observed_var_tags: Vec::new(),
+            history: Vec::new(),
+            values: HashMap::new(),
+            types: HashMap::new(),
             name: name.to_owned(),
+            location: None,
+            analysis: None,
+            isolated_uf: None,
+            latest_only: false,
+        }
+    }
+
+    /// Creates a site located at the given call site, see `Sites::extract_at`.
+    pub fn at(name: &str, file: &str, line: u32, col: u32) -> Self {
+        Site {
+            location: Some((file.to_owned(), line, col)),
+            ..Site::new(name)
+        }
+    }
+
+    /// Binds this site to the analysis with the given id. Observing a tag
+    /// registered by any other analysis is then reported by `update`.
+    pub fn set_analysis(&mut self, analysis: u64) {
+        self.analysis = Some(analysis);
+    }
+
+    /// Whether `update` assigns a variable the type of its latest observation
+    /// only, dropping the types of its earlier values. This includes the versions
+    /// registered by `observe_reassign`, which are then not linked.
+    ///
+    /// Disabled by default: as in the original algorithm, every observation of
+    /// the same variable name is coalesced into a single abstract type, e.g. a
+    /// loop variable bound to a new value on each iteration.
+    pub fn set_latest_only(&mut self, enabled: bool) {
+        self.latest_only = enabled;
+    }
+
+    /// Gives this site its own copy of the interaction sets, see
+    /// `ATI::get_isolated_site`.
+    pub(crate) fn isolate(&mut self, value_uf: UnionFind<Tag>) {
+        self.isolated_uf = Some(value_uf);
+    }
+
+    pub fn is_isolated(&self) -> bool {
+        self.isolated_uf.is_some()
+    }
+
+    /// Site-local interaction sets of an isolated site, with every tag in `tags`
+    /// introduced into them. Returns None if the site is not isolated.
+    pub(crate) fn isolated_uf(&mut self, tags: &[&Tag]) -> Option<&mut UnionFind<Tag>> {
+        let value_uf = self.isolated_uf.as_mut()?;
+        for tag in tags {
+            value_uf.introduce_tag((*tag).clone());
+        }
+        Some(value_uf)
+    }
+
+    /// Same as `update`, but against the site-local interaction sets if the site
+    /// is isolated, which are discarded afterwards. Observed tags registered in
+    /// `value_uf` after the site was isolated are introduced into its local copy.
+    pub(crate) fn update_isolated(
+        &mut self,
+        value_uf: &mut UnionFind<Tag>,
+    ) -> Result<(), AtiError> {
+        let Some(mut local_uf) = self.isolated_uf.take() else {
+            return self.update(value_uf);
+        };
+        for (_, tag) in &self.observed_var_tags {
+            if value_uf.find_no_compress(tag).is_some() {
+                local_uf.introduce_tag(tag.clone());
+            }
+        }
+        self.update(&mut local_uf)
+    }
+
+    /// Whether the tag may be observed at this site, i.e. it was not registered
+    /// by a different analysis than the one this site is bound to.
+    fn same_analysis(&self, tag: &Tag) -> bool {
+        match (self.analysis, tag.analysis()) {
+            (Some(site), Some(tag)) => site == tag,
+            _ => true,
         }
     }
 
     /// Registers a new variable pertaining to this analysis site.
     pub fn observe_var(&mut self, name: &str, var_tag: &Tag) {
+        debug_assert!(
+            self.same_analysis(var_tag),
+            "`{name}` is observed at site `{}` with a tag from a different analysis",
+            self.name
+        );
         self.observed_var_tags.push((name.into(), var_tag.clone()));
+        self.history.push((name.into(), var_tag.clone()));
+    }
+
+    /// Same as `observe_var`, but qualifies the variable with the scope binding
+    /// it, naming it `scope::name`, so that a variable shadowing another of the
+    /// same name (e.g. in a nested block) gets an entry of its own.
+    pub fn observe_scoped(&mut self, scope: &str, name: &str, var_tag: &Tag) {
+        self.observe_var(&format!("{scope}::{name}"), var_tag);
+    }
+
+    /// Registers every primitive field of a struct under analysis, naming each
+    /// `base.path`, e.g. `d.c.a` for the field `c.a` of the variable `d`.
+    pub fn observe_struct(&mut self, base: &str, tags: &impl FieldTags) {
+        for (path, tag) in tags.field_tags() {
+            self.observe_var(&format!("{base}.{path}"), tag);
+        }
+    }
+
+    /// Registers a new version of an already observed variable, e.g. after `x = ...`
+    /// assigns it a fresh value.
+    ///
+    /// Every version of a variable is recorded under the same name, and `update`
+    /// merges the type of each new version into the type of the prior ones in
+    /// `type_uf`. The new value therefore keeps the abstract type of the variable
+    /// it was assigned to, without the caller having to union the stale tag of the
+    /// previous version into `value_uf` by hand.
+    ///
+    /// Versions are linked by coalescing observations by name, so with
+    /// `set_latest_only` the variable takes the type of its latest version only,
+    /// exactly as with `observe_var`.
+    pub fn observe_reassign(&mut self, name: &str, new_tag: &Tag) {
+        debug_assert!(
+            self.var_tags.contains_key(name)
+                || self.observed_var_tags.iter().any(|(var, _)| var == name),
+            "`{name}` is reassigned at site `{}` before ever being observed",
+            self.name
+        );
+        self.observe_var(name, new_tag);
+    }
+
+    /// Attaches a representation of the value of the variable `name`, shown next
+    /// to it in reports, e.g. `x (=10)`. Replaces any previous one.
+    pub fn annotate(&mut self, name: &str, value: &str) {
+        self.values.insert(name.to_owned(), value.to_owned());
+    }
+
+    /// Attaches the name of the type of the variable `name`, shown next to it in
+    /// reports, e.g. `x: u32`. Replaces any previous one.
+    pub fn annotate_type(&mut self, name: &str, type_name: &str) {
+        self.types.insert(name.to_owned(), type_name.to_owned());
+    }
+
+    /// Drops the variable `name` from the analysis of this site, e.g. a temporary
+    /// which was tracked by mistake: its pending observations are discarded, and
+    /// its abstract type is forgotten if it was already updated.
+    ///
+    /// This does not undo any union its values took part in within `value_uf`,
+    /// so other variables which interacted through it still share a type.
+    pub fn unobserve(&mut self, name: &str) {
+        self.observed_var_tags.retain(|(var, _)| var != name);
+        self.history.retain(|(var, _)| var != name);
+        self.var_tags.remove(name);
+        self.values.remove(name);
+        self.types.remove(name);
     }
 
     /// Algorithm from "Dynamic inference of Abstract Types" by Guo et. al.
-    pub fn update(&mut self, value_uf: &mut UnionFind) {
+    ///
+    /// Fails if an observed variable's tag was never registered into `value_uf`,
+    /// i.e. `observe_var` was called without a prior `tracked`/`untracked` call,
+    /// or if it was registered by a different analysis than this site's. Also
+    /// fails with `UnregisteredTag` if the tag of an earlier observation of the
+    /// variable is no longer known to `value_uf`, e.g. after `UnionFind::remove`.
+    ///
+    /// Pending observations are consumed on success, so updating again without
+    /// observing anything new leaves the site untouched. On failure they are kept,
+    /// so the offending variable can be fixed (e.g. with `unobserve`) and the
+    /// update retried.
+    pub fn update(&mut self, value_uf: &mut UnionFind<Tag>) -> Result<(), AtiError> {
         for (new_var, new_var_tag) in &self.observed_var_tags {
-            let new_leader_tag = value_uf.find(new_var_tag).unwrap(); // ? is this unwrap safe? 
-            let new_leader_tag = self.type_uf.introduce_tag(new_leader_tag);
+            if !self.same_analysis(new_var_tag) {
+                return Err(AtiError::CrossAnalysisTag {
+                    site: self.name.clone(),
+                    var: new_var.clone(),
+                });
+            }
+            let new_leader_tag =
+                value_uf
+                    .find(new_var_tag)
+                    .ok_or_else(|| AtiError::UnregisteredTag {
+                        site: self.name.clone(),
+                        var: new_var.clone(),
+                    })?;
+            let (new_leader_tag, _) = self.type_uf.introduce_tag(new_leader_tag);
 
-            if let Some(old_tag) = self.var_tags.get(new_var) {
-                let old_leader_tag = value_uf.find(old_tag).unwrap();
+            let old_tag = match self.var_tags.get(new_var) {
+                Some(old_tag) if !self.latest_only => Some(old_tag),
+                _ => None,
+            };
+            if let Some(old_tag) = old_tag {
+                let old_leader_tag =
+                    value_uf
+                        .find(old_tag)
+                        .ok_or_else(|| AtiError::UnregisteredTag {
+                            site: self.name.clone(),
+                            var: new_var.clone(),
+                        })?;
 
+                // The set of the old value may have been led by another tag since
+                // the last update, which is then new to type_uf.
+                let (old_leader_tag, _) = self.type_uf.introduce_tag(old_leader_tag);
+                self.type_uf.union_tags(old_tag, &old_leader_tag);
                 let merged = self
                     .type_uf
                     .union_tags(&old_leader_tag, &new_leader_tag)
-                    .unwrap();
+                    .expect("both leaders are introduced into type_uf");
                 self.var_tags.insert(new_var.clone(), merged);
             } else {
                 self.var_tags.insert(new_var.clone(), new_leader_tag);
             }
         }
+        self.observed_var_tags.clear();
+        Ok(())
+    }
+
+    /// Same as `update`, but returns the number of distinct abstract types at
+    /// this site afterwards, as a quick measure of how much clustering happened.
+    pub fn update_counted(&mut self, value_uf: &mut UnionFind<Tag>) -> Result<usize, AtiError> {
+        self.update(value_uf)?;
+        Ok(self.type_groups().len())
+    }
+
+    /// Folds the results of another analysis of the same site into this one, e.g.
+    /// when the same function was analyzed under a different `ATI`.
+    ///
+    /// Afterwards, two variables share an abstract type if they did so in either
+    /// site, or if their types contain values which interacted in `value_uf`.
+    ///
+    /// The tags of the abstract types of `other` are introduced into `value_uf`
+    /// if missing, i.e. if `other` belongs to a different analysis, as tags of
+    /// this site's analysis, so later updates resolve them. Pending observations
+    /// of `other` are carried over for the next `update`, except those of a
+    /// different analysis: their values are unknown to `value_uf`, so they are
+    /// dropped, and `other` should be updated before merging.
+    pub fn merge(&mut self, other: &Site, value_uf: &mut UnionFind<Tag>) {
+        for tag in &other.type_uf.index_to_set {
+            if !value_uf.contains(tag) {
+                let tag = match self.analysis {
+                    Some(analysis) => tag.clone().with_analysis(analysis),
+                    None => tag.clone(),
+                };
+                value_uf.introduce_tag(tag);
+            }
+        }
+        self.type_uf.absorb(&other.type_uf);
+        for (var, tag) in &other.var_tags {
+            let merged = match self.var_tags.get(var) {
+                Some(own_tag) => self
+                    .type_uf
+                    .union_tags(own_tag, tag)
+                    .expect("variable tags are always introduced into type_uf"),
+                None => tag.clone(),
+            };
+            self.var_tags.insert(var.clone(), merged);
+        }
+
+        for tag in self.type_uf.index_to_set.clone() {
+            if let Some(value_leader) = value_uf.find(&tag) {
+                let (value_leader, _) = self.type_uf.introduce_tag(value_leader);
+                self.type_uf.union_tags(&tag, &value_leader);
+            }
+        }
+
+        for tag in self.var_tags.values_mut() {
+            *tag = self.type_uf.find(tag).unwrap();
+        }
+        let pending: Vec<(String, Tag)> = other
+            .observed_var_tags
+            .iter()
+            .filter(|(_, tag)| self.same_analysis(tag))
+            .cloned()
+            .collect();
+        self.observed_var_tags.extend(pending);
+        self.history.extend(other.history.iter().cloned());
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        self.location.as_ref()
+    }
+
+    fn key(&self) -> SiteKey {
+        (self.name.clone(), self.location.clone())
+    }
+
+    /// Number of observations waiting to be folded in by the next `update`.
+    pub fn pending_count(&self) -> usize {
+        self.observed_var_tags.len()
+    }
+
+    /// Number of variables with an abstract type, i.e. observed before the last
+    /// `update`.
+    pub fn var_count(&self) -> usize {
+        self.var_tags.len()
+    }
+
+    /// Returns the ATI output of this site, mapping each variable to the leader tag
+    /// of its abstract type as of the last `update`.
+    pub fn get_leaders(&self) -> &VarMap {
+        &self.var_tags
+    }
+
+    /// Assigns each abstract type at this site a small sequential id, returning
+    /// the variables sorted by name alongside the id of their type. Ids are handed
+    /// out in that sorted order, so variables sharing a type share an id and the
+    /// output is stable across runs.
+    pub fn group_ids(&self) -> Vec<(String, usize)> {
+        let vars = self.sorted_vars();
+
+        let mut ids: HashMap<Tag, usize> = HashMap::new();
+        vars.into_iter()
+            .map(|(var, tag)| {
+                let leader = self.type_uf.find_no_compress(tag).unwrap_or(tag.clone());
+                let next_id = ids.len();
+                let id = *ids.entry(leader).or_insert(next_id);
+                (var.clone(), id)
+            })
+            .collect()
+    }
+
+    /// Returns the variables of each abstract type at this site. Every group is
+    /// sorted by name, and groups are sorted by their first variable.
+    pub fn type_groups(&self) -> Vec<Vec<String>> {
+        let mut groups: Vec<Vec<String>> = Vec::new();
+        for (var, id) in self.group_ids() {
+            match groups.get_mut(id) {
+                Some(group) => group.push(var),
+                None => groups.push(vec![var]),
+            }
+        }
+        groups
+    }
+
+    /// Re-derives the abstract types of this site from scratch, replaying every
+    /// observation ever made here against the passed in interaction sets instead
+    /// of the ones the site was updated with. Tags unknown to `value_uf` are
+    /// introduced as singletons. Returns the groups like `type_groups`.
+    pub fn regroup(&self, value_uf: &mut UnionFind<Tag>) -> Vec<Vec<String>> {
+        let mut site = Site::new(&self.name);
+        for (var, tag) in &self.history {
+            value_uf.introduce_tag(tag.clone());
+            site.observe_var(var, tag);
+        }
+        site.update(value_uf)
+            .expect("every observed tag is introduced before updating");
+        site.type_groups()
+    }
+
+    /// Returns the other variables at this site sharing an abstract type with
+    /// `var`, sorted by name. Fails if `var` was never observed here.
+    pub fn peers(&mut self, var: &str) -> Result<Vec<String>, AtiError> {
+        let tag = self
+            .var_tags
+            .get(var)
+            .ok_or_else(|| AtiError::UnknownVar {
+                site: self.name.clone(),
+                var: var.to_owned(),
+            })?
+            .clone();
+        let leader = self
+            .type_uf
+            .find(&tag)
+            .expect("variable tags are always introduced into type_uf");
+
+        let mut peers = Vec::new();
+        for (other, other_tag) in self.var_tags.iter() {
+            if other != var && self.type_uf.find(other_tag).as_ref() == Some(&leader) {
+                peers.push(other.clone());
+            }
+        }
+        peers.sort();
+        Ok(peers)
     }
 
     pub fn report(&self) {
-        println!("=== {} === ", self.name);
-        for (var, tag) in self.var_tags.iter() {
-            println!("{var} -> {tag:?}");
+        self.report_to(&mut io::stdout())
+            .expect("failed to write site report to stdout");
+    }
+
+    /// Writes the same output as `report` into the passed in writer.
+    /// Variables are written in name order.
+    pub fn report_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.report_with(w, false, &|tag| format!("{tag:?}"))
+    }
+
+    /// Same as `report_to`, but only writes the variables sharing their abstract
+    /// type with at least one other variable. Nothing is written if there are
+    /// none, i.e. every variable is a singleton.
+    pub fn report_merged_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.report_with(w, true, &|tag| format!("{tag:?}"))
+    }
+
+    /// Writes the report, printing the leader tag of the type of each variable
+    /// with `label`, and only merged variables if `merged_only` is set.
+    pub(crate) fn report_with<W: Write>(
+        &self,
+        w: &mut W,
+        merged_only: bool,
+        label: &dyn Fn(&Tag) -> String,
+    ) -> io::Result<()> {
+        let merged: Vec<String> = self
+            .type_groups()
+            .into_iter()
+            .filter(|group| group.len() >= 2)
+            .flatten()
+            .collect();
+        if merged_only && merged.is_empty() {
+            return Ok(());
         }
-        println!("\n");
+
+        match &self.location {
+            Some((file, line, col)) => writeln!(w, "=== {} ({file}:{line}:{col}) === ", self.name)?,
+            None => writeln!(w, "=== {} === ", self.name)?,
+        }
+        let vars = self.sorted_vars();
+        for (var, tag) in vars {
+            if merged_only && !merged.contains(var) {
+                continue;
+            }
+            let leader = self.type_uf.find_no_compress(tag).unwrap_or(tag.clone());
+            let leader = label(&leader);
+            let typed = match self.types.get(var) {
+                Some(ty) => format!("{var}: {ty}"),
+                None => var.clone(),
+            };
+            match self.values.get(var) {
+                Some(value) => writeln!(w, "{typed} (={value}) -> {leader}")?,
+                None => writeln!(w, "{typed} -> {leader}")?,
+            }
+        }
+        writeln!(w, "\n")
+    }
+
+    /// Returns every variable alongside its tag, in name order. Only sorts if
+    /// `var_tags` is not already ordered.
+    fn sorted_vars(&self) -> Vec<(&String, &Tag)> {
+        #[allow(unused_mut)]
+        let mut vars: Vec<(&String, &Tag)> = self.var_tags.iter().collect();
+        #[cfg(not(feature = "ordered"))]
+        vars.sort_by_key(|(var, _)| *var);
+        vars
+    }
+
+    /// Returns the leader tag of the abstract type of every variable.
+    pub(crate) fn type_leaders(&self) -> Vec<Tag> {
+        let vars = self.sorted_vars();
+        vars.into_iter()
+            .map(|(_, tag)| self.type_uf.find_no_compress(tag).unwrap_or(tag.clone()))
+            .collect()
     }
 }
 
+/// Fluent helper to produce a finalized `Site` without driving `UnionFind` and
+/// `observe_var` by hand, mostly useful in tests, e.g.
+/// `SiteBuilder::new("f").observe("x", &x_tag).interact(&[&a_tag, &x_tag]).build()`.
+///
+/// Every tag passed in is introduced into the backing `UnionFind` on first use.
+/// Interactions are applied before the site is updated in `build`, exactly like
+/// unions performed before `ATI::update_site`.
+pub struct SiteBuilder {
+    site: Site,
+    value_uf: UnionFind<Tag>,
+}
+
+impl SiteBuilder {
+    pub fn new(name: &str) -> Self {
+        SiteBuilder {
+            site: Site::new(name),
+            value_uf: UnionFind::new(),
+        }
+    }
+
+    /// Observes the variable `name` holding the value tagged with `tag`.
+    pub fn observe(mut self, name: &str, tag: &Tag) -> Self {
+        self.value_uf.introduce_tag(tag.clone());
+        self.site.observe_var(name, tag);
+        self
+    }
+
+    /// Records that the values tagged with `tags` interacted.
+    pub fn interact(mut self, tags: &[&Tag]) -> Self {
+        for tag in tags {
+            self.value_uf.introduce_tag((*tag).clone());
+        }
+        for tags in tags.windows(2) {
+            self.value_uf.union_tags(tags[0], tags[1]);
+        }
+        self
+    }
+
+    /// Updates the site, returning it alongside the backing value `UnionFind`.
+    pub fn build(mut self) -> (Site, UnionFind<Tag>) {
+        self.site
+            .update(&mut self.value_uf)
+            .expect("every observed tag is introduced by the builder");
+        (self.site, self.value_uf)
+    }
+}
+
+/// Called with every site evicted from a bounded `Sites`.
+type EvictFn = Box<dyn FnMut(Site) + Send>;
+
 pub struct Sites {
-    locs: HashMap<String, Site>,
+    locs: HashMap<SiteKey, Site>,
+    /// Maximum number of stashed sites, if bounded.
+    capacity: Option<usize>,
+    /// Keys of the stashed sites, least recently stashed first. Only maintained
+    /// when bounded.
+    recent: VecDeque<SiteKey>,
+    on_evict: Option<EvictFn>,
 }
 impl Sites {
     pub fn new() -> Self {
         Sites {
             locs: HashMap::new(),
+            capacity: None,
+            recent: VecDeque::new(),
+            on_evict: None,
         }
     }
 
+    /// Creates a collection holding at most `n` sites. Stashing a site beyond that
+    /// evicts the least recently stashed (i.e. updated) one, discarding its
+    /// results, which bounds memory in long-running analyses. See `on_evict` to
+    /// save the results of evicted sites.
+    pub fn with_capacity(n: usize) -> Self {
+        Sites {
+            capacity: Some(n),
+            ..Sites::new()
+        }
+    }
+
+    /// Passes every site evicted from now on to `f`, e.g. to flush its report.
+    pub fn on_evict(&mut self, f: impl FnMut(Site) + Send + 'static) {
+        self.on_evict = Some(Box::new(f));
+    }
+
     /// Registers a new site with a given id, or returns
     /// the site with the provided id.
+    ///
+    /// A function running many times extracts, updates and stashes the same site
+    /// on every run, accumulating into its `type_uf`: the first run starts from an
+    /// empty site, and every later update unions each variable's new type with
+    /// the one it had so far. Variables merged in one run therefore stay merged in
+    /// every later run, even if their interaction is not observed again.
     pub fn extract(&mut self, id: &str) -> Site {
-        if !self.locs.contains_key(id) {
-            Site::new(id)
-        } else {
-            self.locs.remove(id).unwrap()
+        self.take(&(id.to_owned(), None))
+            .unwrap_or_else(|| Site::new(id))
+    }
+
+    /// Same as `extract`, but for the site named `name` at the given call site.
+    /// Sites sharing a name at different locations are kept apart, and apart
+    /// from the site of that name without a location.
+    pub fn extract_at(&mut self, name: &str, file: &str, line: u32, col: u32) -> Site {
+        self.take(&(name.to_owned(), Some((file.to_owned(), line, col))))
+            .unwrap_or_else(|| Site::at(name, file, line, col))
+    }
+
+    /// Removes the stashed site without a location named `name`, returning it.
+    pub fn remove(&mut self, name: &str) -> Option<Site> {
+        self.take(&(name.to_owned(), None))
+    }
+
+    fn take(&mut self, key: &SiteKey) -> Option<Site> {
+        let site = self.locs.remove(key)?;
+        if self.capacity.is_some() {
+            self.recent.retain(|recent| recent != key);
         }
+        Some(site)
+    }
+
+    /// Borrows a stashed site without a location, without removing it like
+    /// `extract` does.
+    pub fn get(&self, name: &str) -> Option<&Site> {
+        self.locs.get(&(name.to_owned(), None))
+    }
+
+    /// Borrows the stashed site with the same name and location as `site`.
+    pub(crate) fn get_same(&self, site: &Site) -> Option<&Site> {
+        self.locs.get(&site.key())
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Site> {
+        self.locs.get_mut(&(name.to_owned(), None))
     }
 
     pub fn stash(&mut self, site: Site) {
-        self.locs.insert(site.name.clone(), site);
+        let key = site.key();
+        let Some(capacity) = self.capacity else {
+            self.locs.insert(key, site);
+            return;
+        };
+
+        self.recent.retain(|recent| *recent != key);
+        self.recent.push_back(key.clone());
+        self.locs.insert(key, site);
+        while self.recent.len() > capacity {
+            let oldest = self.recent.pop_front().expect("more sites than capacity");
+            let evicted = self.locs.remove(&oldest).expect("recent sites are stashed");
+            if let Some(on_evict) = &mut self.on_evict {
+                on_evict(evicted);
+            }
+        }
+    }
+
+    /// Removes every stashed site, retaining the allocated capacity.
+    pub fn clear(&mut self) {
+        self.locs.clear();
+        self.recent.clear();
+    }
+
+    /// Number of stashed sites.
+    pub fn len(&self) -> usize {
+        self.locs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locs.is_empty()
+    }
+
+    /// Returns the name of every stashed site once, sorted.
+    pub fn site_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.sorted().iter().map(|site| site.name.clone()).collect();
+        names.dedup();
+        names
+    }
+
+    /// Returns all stashed sites, sorted by name, then location.
+    pub(crate) fn sorted(&self) -> Vec<&Site> {
+        let mut sites: Vec<&Site> = self.locs.values().collect();
+        sites.sort_by(|s1, s2| (&s1.name, &s1.location).cmp(&(&s2.name, &s2.location)));
+        sites
     }
 
     pub fn report(&self) {
-        for (_, site) in self.locs.iter() {
-            site.report();
+        self.report_to(&mut io::stdout())
+            .expect("failed to write sites report to stdout");
+    }
+
+    /// Writes the same output as `report` into the passed in writer.
+    /// Sites are written in name order.
+    pub fn report_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.report_with(w, false, &|tag| format!("{tag:?}"))
+    }
+
+    /// Same as `report_to`, but only writes the merged variables of each site,
+    /// skipping sites without any, see `Site::report_merged_to`.
+    pub fn report_merged_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.report_with(w, true, &|tag| format!("{tag:?}"))
+    }
+
+    /// Writes the report of every site in name order, see `Site::report_with`.
+    pub(crate) fn report_with<W: Write>(
+        &self,
+        w: &mut W,
+        merged_only: bool,
+        label: &dyn Fn(&Tag) -> String,
+    ) -> io::Result<()> {
+        for site in self.sorted() {
+            site.report_with(w, merged_only, label)?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Sites {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Drains every stashed site alongside its name, ordered like the report, e.g. to
+/// hand the sites to a custom reporter without cloning them. Same-named sites at
+/// different locations (see `Sites::extract_at`) are each yielded under their
+/// shared name, and told apart by `Site::location`.
+impl IntoIterator for Sites {
+    type Item = (String, Site);
+    type IntoIter = std::vec::IntoIter<(String, Site)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let mut sites: Vec<(SiteKey, Site)> = self.locs.into_iter().collect();
+        sites.sort_by(|(k1, _), (k2, _)| k1.cmp(k2));
+        sites
+            .into_iter()
+            .map(|((name, _), site)| (name, site))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::union_find::tests::singletons;
+
+    #[test]
+    fn observe_reassign_links_loop_versions() {
+        // next = step; loop twice { next = next * 2 }; sum = next + 1, where
+        // step only interacted with the first value of next, and sum with the last.
+        let (mut value_uf, t) = singletons(5);
+        let (next0, next1, next2, sum, step) = (&t[0], &t[1], &t[2], &t[3], &t[4]);
+        value_uf.union_tags(next0, step);
+        let mut site = Site::new("loop");
+        site.observe_var("step", step);
+        site.observe_var("next", next0);
+        site.update(&mut value_uf).unwrap();
+
+        for next in [next1, next2] {
+            site.observe_reassign("next", next);
+            site.update(&mut value_uf).unwrap();
+        }
+        value_uf.union_tags(next2, sum);
+        site.observe_var("sum", sum);
+        site.update(&mut value_uf).unwrap();
+
+        assert_eq!(site.type_groups(), [vec!["next", "step", "sum"]]);
+        assert_eq!(site.var_count(), 3);
+        // The versions of next were never unioned in value_uf.
+        assert_eq!(value_uf.connected(next0, next2), Some(false));
+    }
+
+    #[test]
+    fn get_leaders() {
+        let (mut value_uf, t) = singletons(3);
+        value_uf.union_tags(&t[0], &t[1]);
+        let mut site = Site::new("f");
+        site.observe_var("a", &t[0]);
+        site.observe_var("b", &t[1]);
+        site.observe_var("c", &t[2]);
+        site.update(&mut value_uf).unwrap();
+
+        let leaders = site.get_leaders().clone();
+        let mut vars: Vec<&String> = leaders.keys().collect();
+        vars.sort();
+        assert_eq!(vars, ["a", "b", "c"]);
+        assert_eq!(leaders["a"], leaders["b"]);
+        assert_eq!(value_uf.find(&leaders["a"]), value_uf.find(&t[0]));
+        assert_eq!(leaders["c"], t[2]);
+
+        site.update(&mut value_uf).unwrap();
+        assert_eq!(*site.get_leaders(), leaders);
+    }
+
+    #[test]
+    fn sites_get_borrows_stashed_site() {
+        let (mut value_uf, t) = singletons(1);
+        let mut site = Site::new("f");
+        site.observe_var("x", &t[0]);
+        site.update(&mut value_uf).unwrap();
+        let mut sites = Sites::new();
+        sites.stash(site);
+
+        let site = sites.get("f").unwrap();
+        assert_eq!(site.get_leaders()["x"], t[0]);
+        assert!(sites.get("g").is_none());
+        // Borrowing does not remove the site.
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites.extract("f").var_count(), 1);
+    }
+
+    #[test]
+    fn update_rejects_tags_of_other_analyses() {
+        let mut value_uf = UnionFind::new();
+        let (foreign, _) = value_uf.introduce_tag(Tag::new(&0).with_analysis(2));
+        let mut site = Site::new("f");
+        site.set_analysis(1);
+        // Bypasses the debug assertion of observe_var, as in release builds.
+        site.observed_var_tags.push(("x".to_owned(), foreign));
+        assert_eq!(
+            site.update(&mut value_uf),
+            Err(AtiError::CrossAnalysisTag {
+                site: "f".to_owned(),
+                var: "x".to_owned(),
+            })
+        );
+        assert_eq!(site.pending_count(), 1);
+    }
+
+    #[test]
+    fn observe_struct_prefixes_nested_paths() {
+        /// Tags of `struct Data { a: u32, b: String, c: Inner }`, where
+        /// `struct Inner { a: u32 }`, as derived by `AtiTagged`.
+        struct DataTag {
+            a_tag: Tag,
+            b_tag: Tag,
+            c_tag: InnerTag,
+        }
+        struct InnerTag {
+            a_tag: Tag,
+        }
+        impl FieldTags for InnerTag {
+            fn field_tags(&self) -> Vec<(String, &Tag)> {
+                vec![("a".to_owned(), &self.a_tag)]
+            }
+        }
+        impl FieldTags for DataTag {
+            fn field_tags(&self) -> Vec<(String, &Tag)> {
+                let mut tags = vec![("a".to_owned(), &self.a_tag), ("b".to_owned(), &self.b_tag)];
+                for (path, tag) in self.c_tag.field_tags() {
+                    tags.push((format!("c.{path}"), tag));
+                }
+                tags
+            }
+        }
+
+        let (mut value_uf, t) = singletons(3);
+        let d_tag = DataTag {
+            a_tag: t[0].clone(),
+            b_tag: t[1].clone(),
+            c_tag: InnerTag {
+                a_tag: t[2].clone(),
+            },
+        };
+        let mut site = Site::new("uses_structs");
+        site.observe_struct("d", &d_tag);
+        assert_eq!(site.pending_count(), 3);
+        site.update(&mut value_uf).unwrap();
+
+        let leaders = site.get_leaders();
+        assert_eq!(leaders["d.a"], t[0]);
+        assert_eq!(leaders["d.b"], t[1]);
+        assert_eq!(leaders["d.c.a"], t[2]);
+    }
+
+    #[test]
+    fn site_builder_reproduces_doubled_func() {
+        let t: Vec<Tag> = (0..7).map(|_| Tag::new(&())).collect();
+        let [x, y, a, b, result, test, merged] = [&t[0], &t[1], &t[2], &t[3], &t[4], &t[5], &t[6]];
+        let (site, mut value_uf) = SiteBuilder::new("doubled_func")
+            .observe("x", x)
+            .observe("y", y)
+            .observe("a", a)
+            .observe("b", b)
+            .interact(&[a, x, result])
+            .observe("result", result)
+            .interact(&[b, y, test])
+            .observe("test", test)
+            .observe("merged", merged)
+            .build();
+
+        assert_eq!(
+            site.type_groups(),
+            [
+                vec!["a", "result", "x"],
+                vec!["b", "test", "y"],
+                vec!["merged"]
+            ]
+        );
+        assert_eq!(site.pending_count(), 0);
+        assert_eq!(value_uf.num_sets(), 3);
+    }
+
+    #[test]
+    fn extract_at_keeps_locations_apart() {
+        let (mut value_uf, t) = singletons(2);
+        let mut sites = Sites::new();
+        for (line, tag) in [(10, &t[0]), (20, &t[1])] {
+            let mut site = sites.extract_at("f", "main.rs", line, 5);
+            site.observe_var("x", tag);
+            site.update(&mut value_uf).unwrap();
+            sites.stash(site);
+        }
+        assert_eq!(sites.len(), 2);
+        assert_eq!(sites.site_names(), ["f"]);
+        assert!(sites.get("f").is_none());
+
+        let site = sites.extract_at("f", "main.rs", 20, 5);
+        assert_eq!(site.location(), Some(&("main.rs".to_owned(), 20, 5)));
+        assert_eq!(site.get_leaders()["x"], t[1]);
+        sites.stash(site);
+
+        let mut out = Vec::new();
+        sites.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("=== f (main.rs:10:5) === \n"));
+        assert!(report.contains("=== f (main.rs:20:5) === \n"));
+    }
+
+    #[test]
+    fn unobserve_drops_the_variable() {
+        let (mut value_uf, t) = singletons(3);
+        let mut site = Site::new("f");
+        site.observe_var("keep", &t[0]);
+        site.observe_var("tmp", &t[1]);
+        site.unobserve("tmp");
+        site.update(&mut value_uf).unwrap();
+
+        let mut out = Vec::new();
+        site.report_to(&mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+        assert!(report.contains("keep -> "));
+        assert!(!report.contains("tmp"));
+
+        // Also forgets the type of an already updated variable.
+        site.observe_var("late", &t[2]);
+        site.update(&mut value_uf).unwrap();
+        site.unobserve("late");
+        assert_eq!(site.type_groups(), [vec!["keep"]]);
+    }
+
+    #[test]
+    fn update_follows_releadered_old_values() {
+        let (mut value_uf, t) = singletons(4);
+        value_uf.union_tags(&t[1], &t[2]);
+        let mut site = Site::new("f");
+        site.observe_var("x", &t[0]);
+        site.update(&mut value_uf).unwrap();
+        assert_eq!(site.get_leaders()["x"], t[0]);
+
+        // The set of x's old value is now led by t[1], which type_uf never saw.
+        value_uf.union_tags(&t[0], &t[1]);
+        assert_eq!(value_uf.find(&t[0]), Some(t[1].clone()));
+        site.observe_var("x", &t[3]);
+        site.observe_var("y", &t[2]);
+        site.update(&mut value_uf).unwrap();
+
+        assert_eq!(site.type_groups(), [vec!["x", "y"]]);
+    }
+
+    #[test]
+    fn update_twice_is_a_no_op() {
+        let (mut value_uf, t) = singletons(3);
+        value_uf.union_tags(&t[0], &t[1]);
+        let mut site = Site::new("f");
+        site.observe_var("a", &t[0]);
+        site.observe_var("b", &t[1]);
+        site.observe_var("c", &t[2]);
+        site.update(&mut value_uf).unwrap();
+        let leaders = site.get_leaders().clone();
+        let groups = site.type_groups();
+
+        // Unions since the first update are not seen without new observations.
+        value_uf.union_tags(&t[1], &t[2]);
+        site.update(&mut value_uf).unwrap();
+        assert_eq!(site.pending_count(), 0);
+        assert_eq!(*site.get_leaders(), leaders);
+        assert_eq!(site.type_groups(), groups);
+    }
+
+    #[test]
+    fn bounded_sites_evict_the_least_recently_stashed() {
+        let evicted = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sites = Sites::with_capacity(3);
+        let log = evicted.clone();
+        sites.on_evict(move |site| log.lock().unwrap().push(site.name().to_owned()));
+
+        for name in ["s0", "s1", "s2", "s3"] {
+            sites.stash(Site::new(name));
+        }
+        assert_eq!(sites.len(), 3);
+        assert!(sites.get("s0").is_none());
+        assert_eq!(sites.site_names(), ["s1", "s2", "s3"]);
+        assert_eq!(*evicted.lock().unwrap(), ["s0"]);
+
+        // Stashing s1 again makes s2 the least recently stashed.
+        let s1 = sites.extract("s1");
+        sites.stash(s1);
+        sites.stash(Site::new("s4"));
+        assert_eq!(sites.site_names(), ["s1", "s3", "s4"]);
+        assert_eq!(*evicted.lock().unwrap(), ["s0", "s2"]);
+    }
+
+    #[test]
+    fn update_moves_pending_observations_to_variables() {
+        let (mut value_uf, t) = singletons(3);
+        let mut site = Site::new("f");
+        site.observe_var("x", &t[0]);
+        site.observe_var("y", &t[1]);
+        assert_eq!((site.pending_count(), site.var_count()), (2, 0));
+
+        site.update(&mut value_uf).unwrap();
+        assert_eq!((site.pending_count(), site.var_count()), (0, 2));
+
+        site.observe_var("x", &t[2]);
+        assert_eq!((site.pending_count(), site.var_count()), (1, 2));
+        site.update(&mut value_uf).unwrap();
+        assert_eq!((site.pending_count(), site.var_count()), (0, 2));
+    }
+
+    #[test]
+    fn observe_scoped_keeps_shadowed_variables_apart() {
+        let (mut value_uf, t) = singletons(3);
+        value_uf.union_tags(&t[0], &t[2]);
+        let mut site = Site::new("f");
+        site.observe_var("x", &t[0]);
+        site.observe_scoped("inner", "x", &t[1]);
+        site.observe_var("y", &t[2]);
+        site.update(&mut value_uf).unwrap();
+        assert_eq!(site.type_groups(), [vec!["inner::x"], vec!["x", "y"]]);
+    }
+
+    #[test]
+    fn observe_reassign_with_latest_only_keeps_the_latest_version() {
+        // Same loop as in observe_reassign_links_loop_versions.
+        let (mut value_uf, t) = singletons(5);
+        let (next0, next1, next2, sum, step) = (&t[0], &t[1], &t[2], &t[3], &t[4]);
+        value_uf.union_tags(next0, step);
+        let mut site = Site::new("loop");
+        site.set_latest_only(true);
+        site.observe_var("step", step);
+        site.observe_var("next", next0);
+        site.update(&mut value_uf).unwrap();
+
+        for next in [next1, next2] {
+            site.observe_reassign("next", next);
+            site.update(&mut value_uf).unwrap();
+        }
+        value_uf.union_tags(next2, sum);
+        site.observe_var("sum", sum);
+        site.update(&mut value_uf).unwrap();
+
+        assert_eq!(site.type_groups(), [vec!["next", "sum"], vec!["step"]]);
+        assert_eq!(site.var_count(), 3);
+    }
+
+    #[test]
+    fn reassigned_variable_appears_once_with_the_merged_type() {
+        // `x` is bound to a new value on each of three iterations, only the first
+        // of which interacts with `y`.
+        let (mut value_uf, t) = singletons(4);
+        let (x0, x1, x2, y) = (&t[0], &t[1], &t[2], &t[3]);
+        value_uf.union_tags(x0, y);
+        for latest_only in [false, true] {
+            let mut site = Site::new("loop");
+            site.set_latest_only(latest_only);
+            site.observe_var("y", y);
+            for x in [x0, x1, x2] {
+                site.observe_var("x", x);
+                site.update(&mut value_uf).unwrap();
+            }
+            assert_eq!(site.var_count(), 2);
+            if latest_only {
+                assert_eq!(site.type_groups(), [vec!["x"], vec!["y"]]);
+            } else {
+                assert_eq!(site.type_groups(), [vec!["x", "y"]]);
+            }
+        }
+    }
+
+    #[test]
+    fn variables_are_listed_in_name_order() {
+        let names = ["z", "m", "b", "y", "a"];
+        let (mut value_uf, t) = singletons(names.len());
+        let mut site = Site::new("f");
+        for (name, tag) in names.iter().zip(&t) {
+            site.observe_var(name, tag);
+        }
+        site.update(&mut value_uf).unwrap();
+        let vars: Vec<&str> = site
+            .sorted_vars()
+            .into_iter()
+            .map(|(var, _)| var.as_str())
+            .collect();
+        assert_eq!(vars, ["a", "b", "m", "y", "z"]);
+    }
+
+    #[cfg(feature = "ordered")]
+    #[test]
+    fn ordered_var_map_iterates_in_name_order() {
+        let (mut value_uf, t) = singletons(3);
+        let mut site = Site::new("f");
+        site.observe_var("c", &t[0]);
+        site.observe_var("a", &t[1]);
+        site.observe_var("b", &t[2]);
+        site.update(&mut value_uf).unwrap();
+        let vars: Vec<&String> = site.get_leaders().keys().collect();
+        assert_eq!(vars, ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn update_counted_of_doubled_func() {
+        // Two calls of doubled_func, only the second one reaching `merged`.
+        let (mut value_uf, t) = singletons(13);
+        let mut site = Site::new("doubled_func");
+        let mut counts = Vec::new();
+        for (call, reaches_merged) in [(&t[..6], false), (&t[6..], true)] {
+            let [x, y, a, b, result, test] = [0, 1, 2, 3, 4, 5].map(|i| &call[i]);
+            value_uf.union_tags(a, x);
+            value_uf.union_tags(x, result);
+            value_uf.union_tags(b, y);
+            value_uf.union_tags(y, test);
+            for (var, tag) in [("x", x), ("y", y), ("a", a), ("b", b)] {
+                site.observe_var(var, tag);
+            }
+            site.observe_var("result", result);
+            site.observe_var("test", test);
+            if reaches_merged {
+                site.observe_var("merged", &call[6]);
+            }
+            counts.push(site.update_counted(&mut value_uf).unwrap());
+        }
+        assert_eq!(counts, [2, 3]);
+    }
+
+    #[test]
+    fn into_iter_yields_same_named_sites_apart() {
+        let mut sites = Sites::new();
+        sites.stash(Site::new("f"));
+        for line in [20, 10] {
+            sites.stash(Site::at("f", "main.rs", line, 5));
         }
+        let sites: Vec<(String, Option<Location>)> = sites
+            .into_iter()
+            .map(|(name, site)| (name, site.location().cloned()))
+            .collect();
+        let at = |line| Some(("main.rs".to_owned(), line, 5));
+        assert_eq!(
+            sites,
+            [
+                ("f".to_owned(), None),
+                ("f".to_owned(), at(10)),
+                ("f".to_owned(), at(20)),
+            ]
+        );
     }
 }
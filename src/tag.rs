@@ -1,12 +1,466 @@
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+use std::any::TypeId;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::union_find::HeapSize;
+
+/// Monotonically increasing counter used to stamp each newly created tag, so that
+/// a stack slot which is freed and later reused by an unrelated value does not
+/// produce a tag equal to the one created for the original value.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Identity of a value under analysis, derived from its address and a generation.
+///
+/// A tag may optionally carry a human-readable `label` (usually the name of the
+/// variable the value was first bound to). The label is purely informational:
+/// equality, ordering and hashing only consider the address and generation, so a
+/// labeled tag and an unlabeled clone of it are interchangeable.
+///
+/// Similarly, `analysis` optionally records the id of the `ATI` instance which
+/// registered the tag, so that tags leaking between analyses can be detected.
+///
+/// Generations restart at 0 in every process, so deserializing a tag (see the
+/// `serde` feature) moves the counter past its generation. Tags created after
+/// reloading a previous run's tags therefore never collide with them.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "SerializedTag"))]
 pub struct Tag {
     addr: String,
+    generation: u64,
+    label: Option<String>,
+    analysis: Option<u64>,
+}
+
+/// Deserialized form of a `Tag`, see `From<SerializedTag> for Tag`.
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct SerializedTag {
+    addr: String,
+    generation: u64,
+    label: Option<String>,
+    analysis: Option<u64>,
+}
+
+#[cfg(feature = "serde")]
+impl From<SerializedTag> for Tag {
+    fn from(tag: SerializedTag) -> Self {
+        NEXT_GENERATION.fetch_max(tag.generation + 1, Ordering::Relaxed);
+        Tag {
+            addr: tag.addr,
+            generation: tag.generation,
+            label: tag.label,
+            analysis: tag.analysis,
+        }
+    }
 }
 
 impl Tag {
     pub fn new<T>(value: &T) -> Self {
         Tag {
             addr: format!("{:p}", value),
+            generation: NEXT_GENERATION.fetch_add(1, Ordering::Relaxed),
+            label: None,
+            analysis: None,
+        }
+    }
+
+    /// Creates a tag keyed on the address and the concrete type of the value, so
+    /// values of different types (e.g. a `u32` and an `i32` at the same address)
+    /// never produce equal tags. Typed tags never compare equal to untyped ones.
+    ///
+    /// Like `from_hash`, the tag carries no generation: tagging the same address
+    /// with the same type again produces the same tag, even if the slot was
+    /// reused by an unrelated value in the meantime.
+    pub fn new_typed<T: 'static>(value: &T) -> Self {
+        let mut hasher = DefaultHasher::new();
+        TypeId::of::<T>().hash(&mut hasher);
+        Tag {
+            addr: format!("{:p}@{:016x}", value, hasher.finish()),
+            generation: 0,
+            label: None,
+            analysis: None,
+        }
+    }
+
+    /// Creates a tag for the value `value` points to rather than the pointer
+    /// itself, e.g. the heap allocation of a `Box`, which keeps its address when
+    /// the box is moved.
+    ///
+    /// The tag is keyed on the pointee address only and, like `from_hash`,
+    /// carries no generation: tagging the box again after moving it produces an
+    /// equal tag. A later allocation reusing the same address does as well.
+    ///
+    /// Exactly one level of indirection is stripped from `T`: if `T` is `&&u32`,
+    /// the tag refers to the `&u32` it points to, not to the `u32`. Dereference
+    /// the argument first (e.g. `Tag::new_deref(*value)`) to go one level deeper.
+    pub fn new_deref<T: Deref>(value: &T) -> Self {
+        Tag {
+            addr: format!("{:p}", value.deref()),
+            generation: 0,
+            label: None,
+            analysis: None,
+        }
+    }
+
+    /// Same as `new`, but stamps the tag with a label for readable reports.
+    pub fn labeled<T>(value: &T, label: &str) -> Self {
+        Tag::new(value).with_label(label)
+    }
+
+    /// Creates a tag keyed on the content of the value rather than its address.
+    ///
+    /// Unlike `new`, every value hashing to the same content produces the same tag,
+    /// regardless of where it lives or when it was created. Since the analysis
+    /// treats equal tags as the same value, two variables holding equal content
+    /// (e.g. both `42`) are considered to have interacted without any explicit
+    /// union. Content tags never compare equal to address based tags.
+    pub fn from_hash<T: Hash>(value: &T) -> Self {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        Tag {
+            addr: format!("#{:016x}", hasher.finish()),
+            generation: 0,
+            label: None,
+            analysis: None,
+        }
+    }
+
+    /// Creates a tag from a fixed integer rather than an address, so that tests
+    /// can build known partitions deterministically. Equal ids produce equal
+    /// tags, and raw tags never compare equal to address or content based ones.
+    pub fn from_raw(id: u64) -> Self {
+        Tag {
+            addr: format!("@{id}"),
+            generation: 0,
+            label: None,
+            analysis: None,
+        }
+    }
+
+    /// Replaces the label of this tag.
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = Some(label.to_owned());
+        self
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// Records the id of the analysis registering this tag.
+    pub fn with_analysis(mut self, analysis: u64) -> Self {
+        self.analysis = Some(analysis);
+        self
+    }
+
+    pub fn analysis(&self) -> Option<u64> {
+        self.analysis
+    }
+}
+
+/// Compact handle to a tag stored in a `TagInterner`, usable as the SetId of a
+/// `UnionFind` in place of `Tag`, so that each address string is stored once in
+/// the interner rather than twice in every `UnionFind` holding it.
+///
+/// Handles are ordered by interning order rather than like the tags they stand
+/// for, so a `UnionFind<InternedTag>` forms the same sets as a `UnionFind<Tag>`
+/// driven identically, but may elect different leaders among equally sized sets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InternedTag(u32);
+
+impl HeapSize for InternedTag {
+    fn heap_bytes(&self) -> usize {
+        0
+    }
+}
+
+/// Arena storing every interned tag once, handing out an `InternedTag` per
+/// distinct tag (as compared by `Tag`'s equality, i.e. ignoring labels).
+#[derive(Clone, Default)]
+pub struct TagInterner {
+    tags: Vec<Tag>,
+    /// Handles of the interned tags, bucketed by their hash, so that lookups do
+    /// not need a second copy of each tag as key.
+    ids: HashMap<u64, Vec<InternedTag>>,
+}
+
+impl TagInterner {
+    pub fn new() -> Self {
+        TagInterner::default()
+    }
+
+    /// Returns the handle of `tag`, interning it first if it is new.
+    pub fn intern(&mut self, tag: Tag) -> InternedTag {
+        if let Some(id) = self.get(&tag) {
+            return id;
+        }
+        let id =
+            InternedTag(u32::try_from(self.tags.len()).expect("more than u32::MAX tags interned"));
+        self.ids.entry(Self::hash(&tag)).or_default().push(id);
+        self.tags.push(tag);
+        id
+    }
+
+    /// Returns the handle of `tag`, or None if it was never interned.
+    pub fn get(&self, tag: &Tag) -> Option<InternedTag> {
+        self.ids
+            .get(&Self::hash(tag))?
+            .iter()
+            .copied()
+            .find(|id| &self.tags[id.0 as usize] == tag)
+    }
+
+    /// Returns the tag behind `id`, or None if `id` was handed out by another
+    /// interner.
+    pub fn resolve(&self, id: InternedTag) -> Option<&Tag> {
+        self.tags.get(id.0 as usize)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    /// Estimates the heap memory held by the interner in bytes, like
+    /// `UnionFind::memory_bytes`.
+    pub fn memory_bytes(&self) -> usize {
+        let tags = self.tags.capacity() * size_of::<Tag>()
+            + self.tags.iter().map(Tag::heap_bytes).sum::<usize>();
+        // hashbrown stores one control byte per bucket next to each entry.
+        let ids = self.ids.capacity() * (size_of::<(u64, Vec<InternedTag>)>() + 1)
+            + self
+                .ids
+                .values()
+                .map(|ids| ids.capacity() * size_of::<InternedTag>())
+                .sum::<usize>();
+        tags + ids
+    }
+
+    fn hash(tag: &Tag) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        tag.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+/// Implemented by the companion tag structs of structs under analysis (see the
+/// `AtiTagged` derive), exposing the tag of every primitive field.
+pub trait FieldTags {
+    /// Returns the dotted path of every primitive field, relative to the struct,
+    /// alongside its tag. Nested structs contribute their fields prefixed by the
+    /// name of the field holding them, e.g. `c.a`.
+    fn field_tags(&self) -> Vec<(String, &Tag)>;
+}
+
+/// Implemented by values under analysis which hold several primitive fields, so
+/// that all of them can be tracked in one call, see `ATI::tracked_fields`. Unlike
+/// `FieldTags`, no companion tag struct is needed: tags are created on demand.
+pub trait Trackable {
+    /// Returns a fresh tag (see `Tag::new`) for every primitive field, alongside
+    /// its dotted path relative to the value, e.g. `c.a`. Nested values
+    /// contribute their fields prefixed by the name of the field holding them.
+    fn tag_fields(&self) -> Vec<(String, Tag)>;
+}
+
+impl HeapSize for Tag {
+    fn heap_bytes(&self) -> usize {
+        self.addr.capacity() + self.label.as_ref().map_or(0, String::capacity)
+    }
+}
+
+impl PartialEq for Tag {
+    fn eq(&self, other: &Self) -> bool {
+        self.addr == other.addr && self.generation == other.generation
+    }
+}
+
+impl Eq for Tag {}
+
+impl PartialOrd for Tag {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Tag {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (&self.addr, self.generation).cmp(&(&other.addr, other.generation))
+    }
+}
+
+impl Hash for Tag {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.addr.hash(state);
+        self.generation.hash(state);
+    }
+}
+
+impl fmt::Debug for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Tag");
+        if let Some(label) = &self.label {
+            debug.field("label", label);
+        }
+        debug
+            .field("addr", &self.addr)
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+/// Compact, user-facing form of a tag: its label when present, or its address.
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{label}"),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::union_find::UnionFind;
+
+    #[test]
+    fn reused_slot_produces_distinct_tag() {
+        let first = {
+            let x = 1u32;
+            Tag::new(&x)
+        };
+        let second = {
+            let y = 2u32;
+            Tag::new(&y)
+        };
+        assert_ne!(first, second);
+
+        // Worst case: the very same address, tagged twice.
+        let z = 3u32;
+        assert_ne!(Tag::new(&z), Tag::new(&z));
+    }
+
+    #[test]
+    fn clones_are_equal() {
+        let x = 1u32;
+        let tag = Tag::new(&x);
+        assert_eq!(tag, tag.clone());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserializing_moves_generations_past_loaded_tags() {
+        let x = 1u32;
+        let loaded = Tag {
+            generation: 1 << 40,
+            ..Tag::new(&x)
+        };
+        let json = serde_json::to_string(&loaded).unwrap();
+        let reloaded: Tag = serde_json::from_str(&json).unwrap();
+        assert_eq!(reloaded, loaded);
+
+        let fresh = Tag::new(&x);
+        assert!(fresh.generation > loaded.generation);
+        assert_ne!(fresh, reloaded);
+    }
+
+    #[test]
+    fn from_hash_keys_on_content() {
+        let x = 42u32;
+        let y = 42u32;
+        assert_eq!(Tag::from_hash(&x), Tag::from_hash(&y));
+        assert_ne!(Tag::new(&x), Tag::new(&y));
+        assert_ne!(Tag::from_hash(&x), Tag::from_hash(&43u32));
+    }
+
+    #[test]
+    fn display_is_compact_and_shared_by_clones() {
+        let x = 1u32;
+        let labeled = Tag::labeled(&x, "x");
+        assert_eq!(format!("{labeled}"), "x");
+        assert_eq!(format!("{}", labeled.clone()), format!("{labeled}"));
+
+        let raw = Tag::from_raw(5);
+        assert_eq!(format!("{raw}"), "@5");
+        assert_eq!(format!("{}", raw.clone()), "@5");
+        assert_eq!(format!("{}", Tag::new(&x)), format!("{:p}", &x));
+    }
+
+    #[test]
+    fn new_typed_keys_on_type() {
+        let x = 5u32;
+        // SAFETY: u32 and i32 have the same size and alignment.
+        let same_address: &i32 = unsafe { &*(&x as *const u32).cast::<i32>() };
+        assert_eq!(Tag::new_typed(&x), Tag::new_typed(&x));
+        assert_ne!(Tag::new_typed(&x), Tag::new_typed(same_address));
+        assert_ne!(Tag::new_typed(&x), Tag::new(&x));
+    }
+
+    #[test]
+    fn new_deref_survives_moving_a_box() {
+        let boxed = Box::new(7u32);
+        let before = Tag::new_deref(&boxed);
+        let moved = boxed;
+        assert_eq!(Tag::new_deref(&moved), before);
+        assert_ne!(Tag::new(&moved), before);
+
+        let other = Box::new(7u32);
+        assert_ne!(Tag::new_deref(&other), before);
+    }
+
+    #[test]
+    fn from_raw_keys_on_the_id() {
+        assert_eq!(Tag::from_raw(3), Tag::from_raw(3));
+        assert_ne!(Tag::from_raw(3), Tag::from_raw(4));
+        assert_eq!(Tag::from_raw(3).with_label("x"), Tag::from_raw(3));
+        assert!(Tag::from_raw(3) < Tag::from_raw(4));
+        assert_ne!(Tag::from_raw(3), Tag::from_hash(&3u64));
+    }
+
+    #[test]
+    fn interned_union_find_matches_tags_in_less_memory() {
+        let values = [0u64; 256];
+        let tags: Vec<Tag> = values
+            .iter()
+            .enumerate()
+            .map(|(i, v)| Tag::labeled(v, &format!("values[{i}]")))
+            .collect();
+        let mut interner = TagInterner::new();
+        let mut by_tag = UnionFind::new();
+        let mut by_id = UnionFind::new();
+        for tag in &tags {
+            by_tag.introduce_tag(tag.clone());
+            by_id.introduce_tag(interner.intern(tag.clone()));
+        }
+        for i in (0..tags.len()).step_by(3) {
+            let j = (i * 7 + 1) % tags.len();
+            by_tag.union_tags(&tags[i], &tags[j]);
+            by_id.union_tags(
+                &interner.intern(tags[i].clone()),
+                &interner.intern(tags[j].clone()),
+            );
+        }
+
+        for t1 in &tags {
+            for t2 in &tags {
+                let (i1, i2) = (interner.get(t1).unwrap(), interner.get(t2).unwrap());
+                assert_eq!(by_tag.connected(t1, t2), by_id.connected(&i1, &i2));
+            }
         }
+        assert_eq!(interner.len(), tags.len());
+        assert_eq!(
+            interner.resolve(interner.get(&tags[5]).unwrap()),
+            Some(&tags[5])
+        );
+        assert!(by_id.memory_bytes() + interner.memory_bytes() < by_tag.memory_bytes());
     }
 }
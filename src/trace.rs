@@ -0,0 +1,77 @@
+use crate::{ati::JsonReader, error::AtiError};
+
+/// Single step of a recorded execution, replayed against an analysis by
+/// `ATI::replay`, so that traces can be collected once and analyzed offline.
+///
+/// Values are identified by integer ids rather than addresses, and are tagged
+/// with `Tag::from_raw`, so equal ids always refer to the same value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// The value `tag` was bound to `var` at `site`, see `ATI::tracked`.
+    Track { site: String, var: String, tag: u64 },
+    /// The values `tags` interacted, see `ATI::union_tags`.
+    Union { tags: Vec<u64> },
+    /// The site `site` was left, see `ATI::update_site`.
+    Update { site: String },
+}
+
+impl TraceEvent {
+    /// Parses one line of a trace: a JSON object with an `op` key (`track`,
+    /// `union` or `update`) and the keys that op needs among `site`, `var` and
+    /// `tags`, e.g. `{"op": "track", "site": "f", "var": "x", "tags": [1]}`.
+    /// `track` takes exactly one tag. Unknown keys are rejected.
+    pub fn parse(line: &str) -> Result<TraceEvent, AtiError> {
+        let mut reader = JsonReader::new(line);
+        let mut op = None;
+        let mut site = None;
+        let mut var = None;
+        let mut tags = None;
+        reader.expect('{')?;
+        loop {
+            let key = reader.string()?;
+            reader.expect(':')?;
+            match key.as_str() {
+                "op" => op = Some(reader.string()?),
+                "site" => site = Some(reader.string()?),
+                "var" => var = Some(reader.string()?),
+                "tags" => tags = Some(Self::tags(&mut reader)?),
+                _ => return Err(reader.error(&format!("unexpected key `{key}`"))),
+            }
+            if reader.eat('}') {
+                break;
+            }
+            reader.expect(',')?;
+        }
+        reader.end()?;
+
+        match (op.as_deref(), site, var, tags) {
+            (Some("track"), Some(site), Some(var), Some(tags)) if tags.len() == 1 => {
+                Ok(TraceEvent::Track {
+                    site,
+                    var,
+                    tag: tags[0],
+                })
+            }
+            (Some("union"), None, None, Some(tags)) => Ok(TraceEvent::Union { tags }),
+            (Some("update"), Some(site), None, None) => Ok(TraceEvent::Update { site }),
+            _ => Err(AtiError::InvalidJson(format!(
+                "malformed trace event `{line}`"
+            ))),
+        }
+    }
+
+    fn tags(reader: &mut JsonReader) -> Result<Vec<u64>, AtiError> {
+        let mut tags = Vec::new();
+        reader.expect('[')?;
+        if reader.eat(']') {
+            return Ok(tags);
+        }
+        loop {
+            tags.push(reader.number()? as u64);
+            if reader.eat(']') {
+                return Ok(tags);
+            }
+            reader.expect(',')?;
+        }
+    }
+}
@@ -1,9 +1,12 @@
+use std::cmp::Reverse;
 use std::collections::HashMap;
+use std::hash::Hash;
 
+use crate::error::AtiError;
 use crate::tag::Tag;
 
 /// Implementation of a UnionFind data structure, in which elements are identified via
-/// a unique SetId (which necessarily implements `Eq + Hash + Clone`). This allows
+/// a unique SetId `K` (which necessarily implements `Eq + Hash + Clone + Ord`). This allows
 /// SetId to be a String representation of the address of a particular variable,
 /// any other identifying information, or even a full struct which stores this identifier
 /// alongside whatever useful metadata is helpful for debugging or organizational
@@ -17,84 +20,496 @@ use crate::tag::Tag;
 /// whatever metadata was associated with it). `find(SetId)` will locate the SetId
 /// of the set leader.
 ///
-/// `rank` is used for determining which direction to perform the union, ultimately
-/// just the standard optimization done with UnionFind structures.
-pub struct UnionFind {
-    id_to_index: HashMap<Tag, usize>,
-    pub index_to_set: Vec<Tag>,
+/// `size` is used for determining which direction to perform the union, ultimately
+/// just the standard optimization done with UnionFind structures. `size[i]` is the
+/// number of elements in the set led by `i`, only meaningful while `i` is a leader.
+/// A size never exceeds the number of slots in `parent`, which itself fits in a
+/// `usize`, so unions can never overflow (as a rank counter could, in principle).
+///
+/// With the `serde` feature enabled, the full internal state can be persisted and
+/// reloaded, so interactions can be accumulated across multiple program executions.
+///
+/// `K` defaults to `Tag`, the address based identity used by the analysis, but any
+/// other identifier (e.g. a `u64` hash of a value's content) can be used instead.
+#[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(
+        serialize = "K: serde::Serialize",
+        deserialize = "K: serde::Deserialize<'de> + Eq + Hash"
+    ))
+)]
+pub struct UnionFind<K = Tag> {
+    #[cfg_attr(feature = "serde", serde(with = "id_pairs"))]
+    id_to_index: HashMap<K, usize>,
+    pub index_to_set: Vec<K>,
+    parent: Vec<usize>,
+    size: Vec<usize>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    compression: CompressionStrategy,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    progress: Progress,
+}
+
+/// Number of elements processed between two progress reports.
+const PROGRESS_INTERVAL: usize = 1 << 12;
+
+/// Optional callback receiving `(processed, total)` while long operations run,
+/// see `UnionFind::set_progress`. Not carried over by clones.
+#[derive(Default)]
+struct Progress(Option<Box<dyn FnMut(usize, usize) + Send>>);
+
+impl Progress {
+    /// Reports every `PROGRESS_INTERVAL` elements, and once done.
+    fn tick(&mut self, processed: usize, total: usize) {
+        if let Some(cb) = &mut self.0
+            && (processed.is_multiple_of(PROGRESS_INTERVAL) || processed == total)
+        {
+            cb(processed, total);
+        }
+    }
+}
+
+impl Clone for Progress {
+    fn clone(&self) -> Self {
+        Progress(None)
+    }
+}
+
+/// How `find` shortens the paths it walks. Both strategies always find the same
+/// leaders, they only differ in the shape the trees are left in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CompressionStrategy {
+    /// Walks to the root, then points every element along the path directly at
+    /// it, touching each element twice.
+    #[default]
+    FullTwoPass,
+    /// Points every other element along the path at its grandparent in a single
+    /// pass, which is often faster when finds and unions are interleaved.
+    PathHalving,
+}
+
+/// Saved state of a `UnionFind`, see `UnionFind::snapshot`.
+#[derive(Clone, Debug)]
+pub struct Snapshot<K = Tag> {
+    id_to_index: HashMap<K, usize>,
+    index_to_set: Vec<K>,
     parent: Vec<usize>,
-    rank: Vec<usize>,
+    size: Vec<usize>,
 }
 
-impl UnionFind {
+/// Immutable form of a `UnionFind`, see `UnionFind::freeze`. Every element points
+/// directly at its leader, so queries only need a shared reference and the
+/// structure can be shared across threads.
+#[derive(Clone)]
+pub struct FrozenUnionFind<K = Tag> {
+    id_to_index: HashMap<K, usize>,
+    index_to_set: Vec<K>,
+    leader: Vec<usize>,
+}
+
+impl<K: Eq + Hash + Clone> FrozenUnionFind<K> {
+    /// Find the leader SetId of the set the passed in SetId belongs to.
+    pub fn find(&self, tag: &K) -> Option<K> {
+        let index = self.id_to_index.get(tag)?;
+        Some(self.index_to_set[self.leader[*index]].clone())
+    }
+
+    /// Checks whether the two passed in SetIds belong to the same set.
+    /// Returns None if either SetId is unknown.
+    pub fn connected(&self, t1: &K, t2: &K) -> Option<bool> {
+        let i1 = self.id_to_index.get(t1)?;
+        let i2 = self.id_to_index.get(t2)?;
+        Some(self.leader[*i1] == self.leader[*i2])
+    }
+}
+
+/// Shape of the trees of a `UnionFind`, see `UnionFind::depth_stats`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DepthStats {
+    /// Number of parent links from the deepest element to its leader.
+    pub max_depth: usize,
+    /// Average number of parent links from an element to its leader.
+    pub avg_depth: f64,
+    /// Number of elements, i.e. SetIds currently known.
+    pub elements: usize,
+}
+
+impl<K: Eq + Hash + Clone + Ord> UnionFind<K> {
     /// Creates a new UnionFind
     pub fn new() -> Self {
         Self {
             id_to_index: HashMap::new(),
             index_to_set: Vec::new(),
             parent: Vec::new(),
-            rank: Vec::new(),
+            size: Vec::new(),
+            compression: CompressionStrategy::default(),
+            progress: Progress::default(),
         }
     }
 
+    /// Creates a new UnionFind with room for `n` elements before reallocating
+    pub fn with_capacity(n: usize) -> Self {
+        Self {
+            id_to_index: HashMap::with_capacity(n),
+            index_to_set: Vec::with_capacity(n),
+            parent: Vec::with_capacity(n),
+            size: Vec::with_capacity(n),
+            compression: CompressionStrategy::default(),
+            progress: Progress::default(),
+        }
+    }
+
+    /// Registers a callback receiving `(processed, total)` periodically while
+    /// `absorb`, `compact` and `sets` run over many elements, e.g. to show a
+    /// progress bar. Replaces any previous callback.
+    pub fn set_progress(&mut self, cb: Box<dyn FnMut(usize, usize) + Send>) {
+        self.progress = Progress(Some(cb));
+    }
+
+    /// Selects how paths are compressed by subsequent finds.
+    pub fn with_compression(mut self, compression: CompressionStrategy) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Removes every element, retaining the allocated capacity. Afterwards the
+    /// structure behaves exactly like a freshly created one.
+    pub fn clear(&mut self) {
+        self.id_to_index.clear();
+        self.index_to_set.clear();
+        self.parent.clear();
+        self.size.clear();
+    }
+
     /// Creates a new unique element in its own set, to be tracked
     /// within this UnionFind. Duplicate SetIds are disallowed.
     ///
-    /// Returns Some(i) if this SetId already corresponds to some set
-    /// at parent[i] with rank[i]. Returns None if this operation created
-    /// a new set.
-    pub fn make_set<V>(&mut self, var: &V) -> Tag {
-        let id = Tag::new(var);
-        self.introduce_tag(id)
-    }
-
-    /// Similar to make_set, but does not create a new tag out of a variable
-    /// just accepts an existing tag as input
-    pub fn introduce_tag(&mut self, id: Tag) -> Tag {
+    /// Returns the SetId alongside whether this operation created a new set.
+    /// If the SetId already corresponds to some set, it is left untouched and
+    /// `false` is returned.
+    pub fn introduce_tag(&mut self, id: K) -> (K, bool) {
         if self.id_to_index.contains_key(&id) {
-            // return Some(*self.id_to_index.get(&id).unwrap());
-            return id;
+            return (id, false);
         }
 
         let index = self.parent.len();
         self.id_to_index.insert(id.clone(), index);
         self.index_to_set.push(id.clone());
         self.parent.push(index);
-        self.rank.push(0);
+        self.size.push(1);
 
-        return id;
+        (id, true)
     }
 
-    fn get_index(&self, id: &Tag) -> Option<usize> {
+    fn get_index(&self, id: &K) -> Option<usize> {
         self.id_to_index.get(id).copied()
     }
 
+    /// Checks whether the passed in SetId was introduced (and not since removed),
+    /// without the `&mut self` required by `find`.
+    pub fn contains(&self, tag: &K) -> bool {
+        self.id_to_index.contains_key(tag)
+    }
+
     /// Find the leader SetId which represents the set that
     /// the passed in SetId identifies.
-    pub fn find(&mut self, tag: &Tag) -> Option<Tag> {
+    pub fn find(&mut self, tag: &K) -> Option<K> {
         let index = self.get_index(tag)?;
         let leader_index = self.find_index(index);
         Some(self.index_to_set[leader_index].clone())
     }
 
+    /// Same as `find`, but does not perform path compression, so it only
+    /// requires a shared reference.
+    pub fn find_no_compress(&self, tag: &K) -> Option<K> {
+        let index = self.get_index(tag)?;
+        let leader_index = self.find_index_no_compress(index);
+        Some(self.index_to_set[leader_index].clone())
+    }
+
+    /// Checks whether the two passed in SetIds currently belong to the same set.
+    /// Returns None if either SetId was never introduced.
+    pub fn connected(&mut self, t1: &K, t2: &K) -> Option<bool> {
+        let i1 = self.get_index(t1)?;
+        let i2 = self.get_index(t2)?;
+        Some(self.find_index(i1) == self.find_index(i2))
+    }
+
+    /// Counts the number of distinct sets currently tracked, compressing
+    /// every path along the way.
+    pub fn num_sets(&mut self) -> usize {
+        (0..self.parent.len())
+            .filter(|&i| self.find_index(i) == i && self.is_live(i))
+            .count()
+    }
+
+    /// Returns the leader SetId of every set once, in the order the leaders were
+    /// introduced, compressing every path along the way.
+    pub fn leaders(&mut self) -> Vec<K> {
+        let leaders: Vec<usize> = (0..self.parent.len())
+            .filter(|&i| self.find_index(i) == i && self.is_live(i))
+            .collect();
+        leaders
+            .into_iter()
+            .map(|i| self.index_to_set[i].clone())
+            .collect()
+    }
+
+    /// Returns the number of elements in the set the passed in SetId belongs to.
+    pub fn set_size(&mut self, tag: &K) -> Option<usize> {
+        let index = self.get_index(tag)?;
+        let leader_index = self.find_index(index);
+        Some(self.size[leader_index])
+    }
+
     /// Merges the sets which the two passed in id's identify.
     /// Returns the leader SetId of the merged set.
-    pub fn union_tags(&mut self, t1: &Tag, t2: &Tag) -> Option<Tag> {
+    pub fn union_tags(&mut self, t1: &K, t2: &K) -> Option<K> {
         let i1 = self.get_index(t1)?;
         let i2 = self.get_index(t2)?;
         let leader_index = self.union_indices(i1, i2);
         Some(self.index_to_set[leader_index].clone())
     }
 
-    /// Internal find function w/ path compression
+    /// Introduces every SetId from `other` into this UnionFind and replays its
+    /// partition, so that the result is the join of both partitions: any two
+    /// SetIds connected in either structure end up connected here.
+    pub fn absorb(&mut self, other: &UnionFind<K>) {
+        let total = other.index_to_set.len();
+        for (i, id) in other.index_to_set.iter().enumerate() {
+            self.progress.tick(i, total);
+            if !other.is_live(i) {
+                continue;
+            }
+            let leader = &other.index_to_set[other.find_index_no_compress(i)];
+            self.introduce_tag(id.clone());
+            self.introduce_tag(leader.clone());
+            self.union_tags(id, leader);
+        }
+        self.progress.tick(total, total);
+    }
+
+    /// Checks whether both structures hold the same SetIds partitioned into the
+    /// same sets, regardless of the shape of their trees or which SetIds lead.
+    pub fn equivalent(&mut self, other: &mut UnionFind<K>) -> bool {
+        if self.id_to_index.len() != other.id_to_index.len() {
+            return false;
+        }
+
+        // Every leader of one structure must correspond to exactly one leader of
+        // the other, for all SetIds.
+        let mut to_other: HashMap<K, K> = HashMap::new();
+        let mut to_self: HashMap<K, K> = HashMap::new();
+        let ids: Vec<K> = self.id_to_index.keys().cloned().collect();
+        for id in ids {
+            let Some(other_leader) = other.find(&id) else {
+                return false;
+            };
+            let leader = self.find(&id).expect("every key of id_to_index is known");
+            if *to_other
+                .entry(leader.clone())
+                .or_insert(other_leader.clone())
+                != other_leader
+                || *to_self.entry(other_leader).or_insert(leader.clone()) != leader
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Compresses every path once, turning the structure into an immutable one
+    /// for read-only queries after the analysis completed.
+    pub fn freeze(mut self) -> FrozenUnionFind<K> {
+        let leader = (0..self.parent.len()).map(|i| self.find_index(i)).collect();
+        FrozenUnionFind {
+            id_to_index: self.id_to_index,
+            index_to_set: self.index_to_set,
+            leader,
+        }
+    }
+
+    /// Captures the current state, so that speculative unions performed
+    /// afterwards can be undone with `rollback`. This copies every SetId, so it
+    /// costs as much as cloning the UnionFind.
+    pub fn snapshot(&self) -> Snapshot<K> {
+        Snapshot {
+            id_to_index: self.id_to_index.clone(),
+            index_to_set: self.index_to_set.clone(),
+            parent: self.parent.clone(),
+            size: self.size.clone(),
+        }
+    }
+
+    /// Restores the state captured by `snap`: SetIds introduced after the
+    /// snapshot are forgotten, and SetIds dropped since (e.g. by `clear`) are
+    /// restored at their former indices.
+    pub fn rollback(&mut self, snap: Snapshot<K>) {
+        self.id_to_index = snap.id_to_index;
+        self.index_to_set = snap.index_to_set;
+        self.parent = snap.parent;
+        self.size = snap.size;
+    }
+
+    /// Rebuilds the internal vecs, dropping the slots of removed elements, while
+    /// preserving the partition of every remaining SetId. Remaining elements are
+    /// reindexed contiguously from 0, in their original order. Returns the number
+    /// of slots reclaimed.
+    pub fn compact(&mut self) -> usize {
+        let old_len = self.parent.len();
+        let mut new_index = vec![usize::MAX; old_len];
+        let mut index_to_set = Vec::new();
+        for (i, new) in new_index.iter_mut().enumerate() {
+            if self.is_live(i) {
+                *new = index_to_set.len();
+                index_to_set.push(self.index_to_set[i].clone());
+            }
+        }
+
+        // Every set keeps its leader, unless the leader itself was removed, in
+        // which case its first remaining member takes over.
+        let mut leaders: HashMap<usize, usize> = HashMap::new();
+        let mut parent = Vec::with_capacity(index_to_set.len());
+        let mut size = vec![0; index_to_set.len()];
+        for i in 0..old_len {
+            self.progress.tick(i, old_len);
+            if !self.is_live(i) {
+                continue;
+            }
+            let root = self.find_index(i);
+            let leader = *leaders.entry(root).or_insert(if self.is_live(root) {
+                new_index[root]
+            } else {
+                new_index[i]
+            });
+            parent.push(leader);
+            size[leader] += 1;
+        }
+        self.progress.tick(old_len, old_len);
+
+        self.id_to_index = index_to_set
+            .iter()
+            .enumerate()
+            .map(|(i, id)| (id.clone(), i))
+            .collect();
+        self.index_to_set = index_to_set;
+        self.parent = parent;
+        self.size = size;
+        old_len - self.parent.len()
+    }
+
+    /// Groups every tracked SetId under the leader SetId of its set. Members of
+    /// each set are sorted (by address, for tags), so output is stable.
+    pub fn sets(&mut self) -> HashMap<K, Vec<K>> {
+        let mut sets: HashMap<K, Vec<K>> = HashMap::new();
+        let total = self.index_to_set.len();
+        for i in 0..total {
+            self.progress.tick(i, total);
+            if !self.is_live(i) {
+                continue;
+            }
+            let leader_index = self.find_index(i);
+            sets.entry(self.index_to_set[leader_index].clone())
+                .or_default()
+                .push(self.index_to_set[i].clone());
+        }
+        self.progress.tick(total, total);
+
+        for members in sets.values_mut() {
+            members.sort();
+        }
+        sets
+    }
+
+    /// Merges all SetIds within each group, introducing those not yet known,
+    /// e.g. to rebuild a partition exported through `sets`. Groups overlapping
+    /// each other or existing sets are merged with them, as with `union_tags`.
+    pub fn union_groups(&mut self, groups: &[Vec<K>]) {
+        for group in groups {
+            for id in group {
+                self.introduce_tag(id.clone());
+            }
+            for ids in group.windows(2) {
+                self.union_tags(&ids[0], &ids[1]);
+            }
+        }
+    }
+
+    /// Measures the depth of every element, i.e. how many parent links `find`
+    /// has to follow from it, to check how well paths are compressed. Leaders
+    /// have depth 0. Unlike `find`, this never compresses paths.
+    pub fn depth_stats(&self) -> DepthStats {
+        let mut max_depth = 0;
+        let mut total_depth = 0;
+        let mut elements = 0;
+        for i in (0..self.parent.len()).filter(|&i| self.is_live(i)) {
+            let mut depth = 0;
+            let mut node = i;
+            while self.parent[node] != node {
+                node = self.parent[node];
+                depth += 1;
+            }
+            max_depth = max_depth.max(depth);
+            total_depth += depth;
+            elements += 1;
+        }
+
+        DepthStats {
+            max_depth,
+            avg_depth: if elements == 0 {
+                0.0
+            } else {
+                total_depth as f64 / elements as f64
+            },
+            elements,
+        }
+    }
+
+    /// Whether slot `i` still holds an element, rather than one which was removed
+    /// (see `remove`) and only remains as an interior node of its set.
+    fn is_live(&self, i: usize) -> bool {
+        self.id_to_index.get(&self.index_to_set[i]) == Some(&i)
+    }
+
+    /// Internal find function w/ path compression, following `compression`.
+    /// Iterative, so that long uncompressed chains cannot overflow the stack.
     fn find_index(&mut self, x: usize) -> usize {
-        if self.parent[x] != x {
-            self.parent[x] = self.find_index(self.parent[x]);
+        if self.compression == CompressionStrategy::PathHalving {
+            let mut current = x;
+            while self.parent[current] != current {
+                self.parent[current] = self.parent[self.parent[current]];
+                current = self.parent[current];
+            }
+            return current;
         }
-        self.parent[x]
+
+        // The first pass walks to the root, the second rewrites every parent
+        // along the path to point at it.
+        let root = self.find_index_no_compress(x);
+
+        let mut current = x;
+        while self.parent[current] != root {
+            let next = self.parent[current];
+            self.parent[current] = root;
+            current = next;
+        }
+        root
+    }
+
+    /// Internal find function which only walks parents, leaving them untouched
+    fn find_index_no_compress(&self, x: usize) -> usize {
+        let mut root = x;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        root
     }
 
-    /// Internal union, performing union by rank
+    /// Internal union, performing union by size. On ties the root with the
+    /// smaller SetId stays the leader, so leader selection is order independent.
     fn union_indices(&mut self, x: usize, y: usize) -> usize {
         let x_root = self.find_index(x);
         let y_root = self.find_index(y);
@@ -103,17 +518,704 @@ impl UnionFind {
             return x_root;
         }
 
-        // Union towards larger rank
-        if self.rank[x_root] < self.rank[y_root] {
-            self.parent[x_root] = y_root;
-            y_root
-        } else if self.rank[x_root] > self.rank[y_root] {
-            self.parent[y_root] = x_root;
-            x_root
+        // Attach the smaller tree under the larger one. On ties, the smaller SetId
+        // leads, so the leader does not depend on the order of the arguments.
+        let x_key = (self.size[x_root], Reverse(&self.index_to_set[x_root]));
+        let y_key = (self.size[y_root], Reverse(&self.index_to_set[y_root]));
+        let (leader, child) = if x_key < y_key {
+            (y_root, x_root)
         } else {
-            self.parent[y_root] = x_root;
-            self.rank[x_root] += 1;
-            x_root
+            (x_root, y_root)
+        };
+        self.parent[child] = leader;
+        // Cannot overflow: the two sets are disjoint, so their combined size is
+        // at most the number of elements.
+        self.size[leader] += self.size[child];
+        leader
+    }
+}
+
+/// Heap memory owned by a SetId, see `UnionFind::memory_bytes`.
+pub trait HeapSize {
+    /// Bytes allocated on the heap by this value, excluding its inline size.
+    fn heap_bytes(&self) -> usize;
+}
+
+impl HeapSize for u64 {
+    fn heap_bytes(&self) -> usize {
+        0
+    }
+}
+
+impl<K: HeapSize + Eq + Hash + Clone + Ord> UnionFind<K> {
+    /// Estimates the heap memory held by this structure in bytes: the tables of
+    /// `id_to_index` (by capacity, ignoring the allocator's overhead), the
+    /// `index_to_set`, `parent` and `size` Vecs, and the heap data owned by every
+    /// SetId (e.g. the strings of a `Tag`), which are stored twice. Slots of
+    /// removed elements are counted until reclaimed by `compact`, so this helps
+    /// deciding when compacting pays off.
+    pub fn memory_bytes(&self) -> usize {
+        // hashbrown stores one control byte per bucket next to each entry.
+        let map = self.id_to_index.capacity() * (size_of::<(K, usize)>() + 1);
+        let vecs = self.index_to_set.capacity() * size_of::<K>()
+            + (self.parent.capacity() + self.size.capacity()) * size_of::<usize>();
+        let ids: usize = self
+            .id_to_index
+            .keys()
+            .chain(&self.index_to_set)
+            .map(K::heap_bytes)
+            .sum();
+        map + vecs + ids
+    }
+}
+
+impl UnionFind<Tag> {
+    /// Creates a new tag for the passed in variable and introduces it as a new
+    /// set, see `introduce_tag`. Every `Tag::new` is distinct, even for the same
+    /// variable (see `Tag`), so this always creates a new set. Use
+    /// `introduce_tag` with an existing tag to detect duplicates.
+    pub fn make_set<V>(&mut self, var: &V) -> Tag {
+        let (id, _) = self.introduce_tag(Tag::new(var));
+        id
+    }
+
+    /// Replaces the identity of the element `old` with `new`, keeping its set
+    /// membership. Afterwards `old` is no longer known. Fails if `old` was never
+    /// introduced, or if `new` already identifies some element.
+    pub fn rename_tag(&mut self, old: &Tag, new: Tag) -> Result<(), AtiError> {
+        if self.id_to_index.contains_key(&new) {
+            return Err(AtiError::DuplicateTag(new));
         }
+        let index = self
+            .id_to_index
+            .remove(old)
+            .ok_or_else(|| AtiError::UnknownTag(old.clone()))?;
+
+        self.id_to_index.insert(new.clone(), index);
+        self.index_to_set[index] = new;
+        Ok(())
+    }
+
+    /// Forgets the element `tag`, e.g. once the variable holding it went out of
+    /// scope. The remaining members of its set stay connected; if `tag` was their
+    /// leader, another member is promoted in its place. The slot of the element is
+    /// only reclaimed by `compact`. Fails if `tag` was never introduced.
+    pub fn remove(&mut self, tag: &Tag) -> Result<(), AtiError> {
+        let index = self
+            .id_to_index
+            .remove(tag)
+            .ok_or_else(|| AtiError::UnknownTag(tag.clone()))?;
+        let leader_index = self.find_index(index);
+        self.size[leader_index] -= 1;
+        if leader_index != index || self.size[index] == 0 {
+            return Ok(());
+        }
+
+        // The removed element led other members: the first remaining one takes
+        // over, and the removed slot keeps linking its former children to it.
+        let promoted = (0..self.parent.len())
+            .find(|&i| i != index && self.is_live(i) && self.find_index(i) == index)
+            .expect("a leader with members has a remaining member");
+        self.parent[promoted] = promoted;
+        self.parent[index] = promoted;
+        self.size[promoted] = self.size[index];
+        Ok(())
+    }
+}
+
+impl<K: Eq + Hash + Clone + Ord> Default for UnionFind<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `id_to_index` is keyed on SetIds such as `Tag`, which formats such as JSON cannot
+/// use as a map key, so it is (de)serialized as a list of `(SetId, index)` pairs instead.
+#[cfg(feature = "serde")]
+mod id_pairs {
+    use std::collections::HashMap;
+    use std::hash::Hash;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<K, S>(map: &HashMap<K, usize>, s: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        S: Serializer,
+    {
+        s.collect_seq(map.iter())
+    }
+
+    pub fn deserialize<'de, K, D>(d: D) -> Result<HashMap<K, usize>, D::Error>
+    where
+        K: Deserialize<'de> + Eq + Hash,
+        D: Deserializer<'de>,
+    {
+        let pairs: Vec<(K, usize)> = Vec::deserialize(d)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Creates a UnionFind holding `n` singleton sets, returned in order of
+    /// introduction, which is also their order as tags (see `Tag`).
+    pub(crate) fn singletons(n: usize) -> (UnionFind, Vec<Tag>) {
+        let mut uf = UnionFind::new();
+        let tags = (0..n).map(|_| uf.make_set(&())).collect();
+        (uf, tags)
+    }
+
+    #[test]
+    fn connected() {
+        let (mut uf, t) = singletons(3);
+        uf.union_tags(&t[0], &t[1]);
+        assert_eq!(uf.connected(&t[0], &t[1]), Some(true));
+        assert_eq!(uf.connected(&t[0], &t[2]), Some(false));
+        assert_eq!(uf.connected(&t[0], &Tag::new(&())), None);
+    }
+
+    #[test]
+    fn num_sets() {
+        let (mut uf, t) = singletons(3);
+        assert_eq!(uf.num_sets(), 3);
+        uf.union_tags(&t[0], &t[2]);
+        assert_eq!(uf.num_sets(), 2);
+        assert_eq!(singletons(0).0.num_sets(), 0);
+    }
+
+    #[test]
+    fn sets_sorts_members() {
+        let (mut uf, t) = singletons(5);
+        uf.union_tags(&t[3], &t[1]);
+        uf.union_tags(&t[1], &t[4]);
+        uf.union_tags(&t[2], &t[0]);
+
+        let mut sets: Vec<Vec<Tag>> = uf.sets().into_values().collect();
+        sets.sort();
+        assert_eq!(
+            sets,
+            vec![
+                vec![t[0].clone(), t[2].clone()],
+                vec![t[1].clone(), t[3].clone(), t[4].clone()],
+            ]
+        );
+    }
+
+    #[test]
+    fn find_handles_long_chains() {
+        const N: usize = 100_000;
+        let (mut uf, t) = singletons(N);
+        // Union every element into the tail of the chain built so far.
+        for i in 1..N {
+            uf.union_tags(&t[i], &t[i - 1]);
+        }
+
+        let leader = uf.find(&t[0]).unwrap();
+        assert!(t.iter().all(|tag| uf.find(tag).as_ref() == Some(&leader)));
+        assert_eq!(uf.num_sets(), 1);
+    }
+
+    #[test]
+    fn introduce_tag_reports_duplicates() {
+        let mut uf = UnionFind::new();
+        let x = 1u32;
+        let tag = Tag::new(&x);
+        assert_eq!(uf.introduce_tag(tag.clone()), (tag.clone(), true));
+        assert_eq!(uf.introduce_tag(tag.clone()), (tag.clone(), false));
+        assert_eq!(uf.num_sets(), 1);
+
+        // Each make_set creates a fresh tag, even for the same variable.
+        let other = uf.make_set(&x);
+        assert_ne!(tag, other);
+        assert_eq!(uf.num_sets(), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let values = [1u32, 2, 3, 4, 5];
+        let mut uf = UnionFind::new();
+        let tags: Vec<Tag> = values.iter().map(|v| uf.make_set(v)).collect();
+        uf.union_tags(&tags[0], &tags[1]);
+        uf.union_tags(&tags[3], &tags[4]);
+        uf.union_tags(&tags[1], &tags[4]);
+
+        let json = serde_json::to_string(&uf).unwrap();
+        let mut reloaded: UnionFind = serde_json::from_str(&json).unwrap();
+        for t1 in &tags {
+            for t2 in &tags {
+                assert_eq!(reloaded.connected(t1, t2), uf.connected(t1, t2));
+            }
+        }
+
+        // Values tagged after reloading never collide with the reloaded tags.
+        let fresh = reloaded.make_set(&values[0]);
+        assert_eq!(reloaded.connected(&fresh, &tags[0]), Some(false));
+    }
+
+    #[test]
+    fn absorb_joins_partitions() {
+        let (mut uf, t) = singletons(4);
+        uf.union_tags(&t[0], &t[1]);
+
+        let mut other = UnionFind::new();
+        for tag in &t[1..] {
+            other.introduce_tag(tag.clone());
+        }
+        other.union_tags(&t[1], &t[2]);
+        let lone = Tag::new(&());
+        other.introduce_tag(lone.clone());
+
+        uf.absorb(&other);
+        assert_eq!(uf.connected(&t[0], &t[2]), Some(true));
+        assert_eq!(uf.connected(&t[0], &t[3]), Some(false));
+        assert_eq!(uf.connected(&t[3], &lone), Some(false));
+        assert_eq!(uf.num_sets(), 3);
+    }
+
+    #[test]
+    fn set_size() {
+        let (mut uf, t) = singletons(5);
+        uf.union_tags(&t[0], &t[1]);
+        uf.union_tags(&t[2], &t[3]);
+        uf.union_tags(&t[1], &t[3]);
+        assert_eq!(uf.set_size(&t[2]), Some(4));
+        assert_eq!(uf.set_size(&t[4]), Some(1));
+        assert_eq!(uf.set_size(&Tag::new(&())), None);
+    }
+
+    #[test]
+    fn union_by_size_bounds_height() {
+        const N: u64 = 10_000;
+        let mut uf: UnionFind<u64> = UnionFind::new();
+        for i in 0..N {
+            uf.introduce_tag(i);
+        }
+        // Always pass the root of the growing set first, the worst case for a
+        // union which attaches its first argument under its second.
+        for i in 1..N {
+            let root = uf.find_no_compress(&0).unwrap();
+            uf.union_tags(&root, &i);
+        }
+
+        // Union by size keeps every tree at most log2(N) high.
+        assert!(uf.depth_stats().max_depth <= N.ilog2() as usize);
+        assert_eq!(uf.num_sets(), 1);
+    }
+
+    #[test]
+    fn labels_do_not_affect_membership() {
+        let (mut uf, t) = singletons(2);
+        let labeled = t[0].clone().with_label("x");
+        assert!(!uf.introduce_tag(labeled.clone()).1);
+        uf.union_tags(&labeled, &t[1].clone().with_label("y"));
+        assert_eq!(uf.connected(&t[0], &t[1]), Some(true));
+        assert_eq!(uf.num_sets(), 1);
+    }
+
+    #[test]
+    fn find_no_compress_agrees_with_find() {
+        let (mut uf, t) = singletons(8);
+        for (a, b) in [(0, 1), (2, 3), (0, 2), (4, 5), (6, 4), (0, 6)] {
+            uf.union_tags(&t[a], &t[b]);
+        }
+        let lone = Tag::new(&());
+        uf.introduce_tag(lone.clone());
+
+        let uncompressed: Vec<Option<Tag>> = t.iter().map(|tag| uf.find_no_compress(tag)).collect();
+        let found: Vec<Option<Tag>> = t.iter().map(|tag| uf.find(tag)).collect();
+        assert_eq!(uncompressed, found);
+        assert_eq!(uf.find_no_compress(&lone), Some(lone.clone()));
+        assert_eq!(uf.find_no_compress(&Tag::new(&())), None);
+    }
+
+    #[test]
+    fn keyed_by_u64() {
+        let mut uf: UnionFind<u64> = UnionFind::new();
+        for key in [10, 20, 30, 40] {
+            assert_eq!(uf.introduce_tag(key), (key, true));
+        }
+        assert_eq!(uf.introduce_tag(20), (20, false));
+
+        uf.union_tags(&10, &30);
+        uf.union_tags(&40, &30);
+        assert_eq!(uf.connected(&10, &40), Some(true));
+        assert_eq!(uf.connected(&10, &20), Some(false));
+        assert_eq!(uf.set_size(&40), Some(3));
+        assert_eq!(uf.num_sets(), 2);
+        assert_eq!(uf.find(&50), None);
+    }
+
+    #[test]
+    fn clear_restarts_indices_at_zero() {
+        let (mut uf, t) = singletons(3);
+        uf.union_tags(&t[0], &t[2]);
+        let capacity = uf.parent.capacity();
+        uf.clear();
+        assert_eq!(uf.num_sets(), 0);
+        assert!(!uf.contains(&t[0]));
+        assert_eq!(uf.parent.capacity(), capacity);
+
+        let fresh = Tag::new(&());
+        uf.introduce_tag(fresh.clone());
+        uf.introduce_tag(t[2].clone());
+        assert_eq!(uf.get_index(&fresh), Some(0));
+        assert_eq!(uf.get_index(&t[2]), Some(1));
+        assert_eq!(uf.connected(&fresh, &t[2]), Some(false));
+    }
+
+    #[test]
+    fn with_capacity_does_not_reallocate() {
+        const N: u64 = 1_000;
+        let mut uf: UnionFind<u64> = UnionFind::with_capacity(N as usize);
+        let capacity = uf.parent.capacity();
+        assert!(capacity >= N as usize);
+        for i in 0..N {
+            uf.introduce_tag(i);
+        }
+        for i in 1..N {
+            uf.union_tags(&0, &i);
+        }
+        assert_eq!(uf.parent.capacity(), capacity);
+        assert_eq!(uf.num_sets(), 1);
+        assert_eq!(uf.set_size(&(N - 1)), Some(N as usize));
+    }
+
+    #[test]
+    fn rollback_undoes_unions() {
+        let (mut uf, t) = singletons(4);
+        uf.union_tags(&t[0], &t[1]);
+        let snap = uf.snapshot();
+        uf.union_tags(&t[1], &t[2]);
+        let late = Tag::new(&());
+        uf.introduce_tag(late.clone());
+
+        uf.rollback(snap);
+        assert_eq!(uf.connected(&t[0], &t[1]), Some(true));
+        assert_eq!(uf.connected(&t[0], &t[2]), Some(false));
+        assert!(!uf.contains(&late));
+        assert_eq!(uf.num_sets(), 3);
+    }
+
+    #[test]
+    fn rollback_restores_removed_elements() {
+        let (mut uf, t) = singletons(4);
+        uf.union_tags(&t[0], &t[1]);
+        uf.union_tags(&t[2], &t[3]);
+        let snap = uf.snapshot();
+
+        // Removing the leader promotes another member.
+        uf.remove(&t[0]).unwrap();
+        uf.rollback(snap.clone());
+        assert_eq!(uf.find(&t[1]), Some(t[0].clone()));
+        assert_eq!(uf.set_size(&t[0]), Some(2));
+
+        uf.remove(&t[1]).unwrap();
+        uf.remove(&t[3]).unwrap();
+        assert_eq!(uf.compact(), 2);
+        uf.rollback(snap.clone());
+        assert_eq!(uf.get_index(&t[3]), Some(3));
+        assert_eq!(uf.connected(&t[0], &t[1]), Some(true));
+        assert_eq!(uf.connected(&t[2], &t[3]), Some(true));
+
+        uf.clear();
+        uf.rollback(snap);
+        assert_eq!(uf.num_sets(), 2);
+        assert_eq!(uf.sets().len(), 2);
+    }
+
+    #[test]
+    fn rename_tag_keeps_membership() {
+        let (mut uf, t) = singletons(3);
+        uf.union_tags(&t[0], &t[1]);
+        let renamed = Tag::new(&());
+        uf.rename_tag(&t[1], renamed.clone()).unwrap();
+        assert_eq!(uf.connected(&renamed, &t[0]), Some(true));
+        assert!(!uf.contains(&t[1]));
+
+        assert_eq!(
+            uf.rename_tag(&t[1], Tag::new(&())),
+            Err(AtiError::UnknownTag(t[1].clone()))
+        );
+        assert_eq!(
+            uf.rename_tag(&t[2], renamed.clone()),
+            Err(AtiError::DuplicateTag(renamed))
+        );
+    }
+
+    #[test]
+    fn compact_keeps_surviving_partition() {
+        let (mut uf, t) = singletons(10);
+        // Sets {0, 2, 4, 6, 8} and {1, 3, 5, 7, 9}.
+        for i in 2..10 {
+            uf.union_tags(&t[i % 2], &t[i]);
+        }
+        // Removes half of the elements, including the leader of the odd set.
+        for i in [1, 2, 4, 6, 8] {
+            uf.remove(&t[i]).unwrap();
+        }
+        assert_eq!(uf.compact(), 5);
+
+        let odd = [&t[3], &t[5], &t[7], &t[9]];
+        for tag in odd {
+            assert_eq!(uf.connected(odd[0], tag), Some(true));
+        }
+        assert_eq!(uf.connected(&t[0], &t[3]), Some(false));
+        assert_eq!(uf.set_size(&t[3]), Some(4));
+        assert_eq!(uf.set_size(&t[0]), Some(1));
+        assert!(!uf.contains(&t[1]));
+
+        let mut indices: Vec<usize> = [&t[0], &t[3], &t[5], &t[7], &t[9]]
+            .iter()
+            .map(|tag| uf.get_index(tag).unwrap())
+            .collect();
+        indices.sort();
+        assert_eq!(indices, [0, 1, 2, 3, 4]);
+        assert_eq!(uf.parent.len(), 5);
+        assert_eq!(uf.compact(), 0);
+    }
+
+    #[test]
+    fn remove_non_leader() {
+        let (mut uf, t) = singletons(3);
+        uf.union_tags(&t[0], &t[1]);
+        uf.union_tags(&t[0], &t[2]);
+        uf.remove(&t[1]).unwrap();
+        assert!(!uf.contains(&t[1]));
+        assert_eq!(uf.find(&t[2]), Some(t[0].clone()));
+        assert_eq!(uf.set_size(&t[0]), Some(2));
+    }
+
+    #[test]
+    fn remove_leader_with_members() {
+        let (mut uf, t) = singletons(4);
+        uf.union_tags(&t[0], &t[1]);
+        uf.union_tags(&t[0], &t[2]);
+        assert_eq!(uf.find(&t[2]), Some(t[0].clone()));
+
+        uf.remove(&t[0]).unwrap();
+        let promoted = uf.find(&t[1]).unwrap();
+        assert_ne!(promoted, t[0]);
+        assert_eq!(uf.find(&t[2]), Some(promoted));
+        assert_eq!(uf.set_size(&t[1]), Some(2));
+        assert_eq!(uf.connected(&t[1], &t[3]), Some(false));
+        assert_eq!(uf.num_sets(), 2);
+    }
+
+    #[test]
+    fn remove_singleton() {
+        let (mut uf, t) = singletons(2);
+        uf.remove(&t[0]).unwrap();
+        assert_eq!(uf.num_sets(), 1);
+        assert_eq!(uf.find(&t[0]), None);
+        assert_eq!(uf.remove(&t[0]), Err(AtiError::UnknownTag(t[0].clone())));
+    }
+
+    #[test]
+    fn ties_pick_the_same_leader_in_any_order() {
+        let (mut forward, t) = singletons(4);
+        let mut backward = forward.clone();
+        forward.union_tags(&t[0], &t[1]);
+        forward.union_tags(&t[2], &t[3]);
+        forward.union_tags(&t[0], &t[2]);
+        backward.union_tags(&t[3], &t[2]);
+        backward.union_tags(&t[1], &t[0]);
+        backward.union_tags(&t[3], &t[1]);
+
+        for tag in &t {
+            assert_eq!(forward.find(tag), backward.find(tag));
+        }
+        assert_eq!(forward.leaders(), backward.leaders());
+    }
+
+    #[test]
+    fn depth_stats_of_known_shape() {
+        let (mut uf, _) = singletons(5);
+        // The chain 3 -> 2 -> 1 -> 0, and the singleton 4.
+        uf.parent[1] = 0;
+        uf.parent[2] = 1;
+        uf.parent[3] = 2;
+        uf.size[0] = 4;
+        let parent = uf.parent.clone();
+
+        let stats = uf.depth_stats();
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(stats.avg_depth, 6.0 / 5.0);
+        assert_eq!(stats.elements, 5);
+        assert_eq!(uf.parent, parent);
+    }
+
+    #[test]
+    fn leaders_of_three_sets() {
+        let (mut uf, t) = singletons(6);
+        uf.union_tags(&t[0], &t[1]);
+        uf.union_tags(&t[2], &t[3]);
+        uf.union_tags(&t[3], &t[4]);
+
+        let leaders = uf.leaders();
+        assert_eq!(leaders, [t[0].clone(), t[2].clone(), t[5].clone()]);
+        for tag in &t {
+            assert!(leaders.contains(&uf.find(tag).unwrap()));
+        }
+    }
+
+    #[test]
+    fn path_halving_finds_the_same_leaders() {
+        let (mut two_pass, t) = singletons(10);
+        let mut halving = two_pass
+            .clone()
+            .with_compression(CompressionStrategy::PathHalving);
+        for (a, b) in [
+            (0, 1),
+            (2, 3),
+            (1, 3),
+            (4, 5),
+            (6, 7),
+            (5, 7),
+            (3, 7),
+            (8, 9),
+        ] {
+            assert_eq!(
+                two_pass.union_tags(&t[a], &t[b]),
+                halving.union_tags(&t[a], &t[b])
+            );
+        }
+        for tag in &t {
+            assert_eq!(two_pass.find(tag), halving.find(tag));
+        }
+        assert_eq!(two_pass.leaders(), halving.leaders());
+        assert_eq!(halving.leaders(), [t[0].clone(), t[8].clone()]);
+    }
+
+    #[test]
+    fn equivalent_ignores_tree_shape() {
+        let (mut uf1, t) = singletons(5);
+        let mut uf2 = uf1.clone();
+        for (a, b) in [(0, 1), (1, 2), (3, 4)] {
+            uf1.union_tags(&t[a], &t[b]);
+        }
+        for (a, b) in [(4, 3), (2, 0), (2, 1)] {
+            uf2.union_tags(&t[a], &t[b]);
+        }
+        assert!(uf1.equivalent(&mut uf2));
+        assert!(uf2.equivalent(&mut uf1));
+
+        uf2.union_tags(&t[0], &t[3]);
+        assert!(!uf1.equivalent(&mut uf2));
+        let (mut uf3, _) = singletons(6);
+        assert!(!uf1.equivalent(&mut uf3));
+    }
+
+    #[test]
+    fn frozen_is_queried_from_several_threads() {
+        let (mut uf, t) = singletons(6);
+        for (a, b) in [(0, 1), (1, 2), (3, 4)] {
+            uf.union_tags(&t[a], &t[b]);
+        }
+        let frozen = uf.freeze();
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    assert_eq!(frozen.find(&t[2]), Some(t[0].clone()));
+                    assert_eq!(frozen.find(&t[4]), Some(t[3].clone()));
+                    assert_eq!(frozen.connected(&t[0], &t[2]), Some(true));
+                    assert_eq!(frozen.connected(&t[2], &t[5]), Some(false));
+                    assert_eq!(frozen.find(&Tag::new(&())), None);
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn set_progress_reports_every_interval() {
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut uf: UnionFind<usize> = UnionFind::new();
+        let total = 2 * PROGRESS_INTERVAL + 5;
+        for key in 0..total {
+            uf.introduce_tag(key);
+        }
+        let log = calls.clone();
+        uf.set_progress(Box::new(move |processed, total| {
+            log.lock().unwrap().push((processed, total))
+        }));
+        uf.sets();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            [
+                (0, total),
+                (PROGRESS_INTERVAL, total),
+                (2 * PROGRESS_INTERVAL, total),
+                (total, total)
+            ]
+        );
+        // Clones do not carry the callback over.
+        uf.clone().sets();
+        assert_eq!(calls.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn sizes_never_exceed_the_element_count() {
+        let (mut uf, t) = singletons(64);
+        // Repeated and redundant unions must not count any element twice.
+        for _ in 0..2 {
+            for pair in t.windows(2) {
+                uf.union_tags(&pair[0], &pair[1]);
+                uf.union_tags(&pair[1], &pair[0]);
+            }
+        }
+        assert_eq!(uf.set_size(&t[0]), Some(64));
+        assert!(uf.size.iter().all(|&size| size <= uf.parent.len()));
+    }
+
+    #[test]
+    fn contains_tracks_introduction_and_removal() {
+        let (mut uf, t) = singletons(2);
+        uf.union_tags(&t[0], &t[1]);
+        assert!(uf.contains(&t[0]));
+        assert!(uf.contains(&t[1]));
+        assert!(!uf.contains(&Tag::from_raw(2)));
+        uf.remove(&t[1]).unwrap();
+        assert!(!uf.contains(&t[1]));
+        assert!(uf.contains(&t[0]));
+    }
+
+    #[test]
+    fn memory_bytes_grows_with_every_element() {
+        let mut uf = UnionFind::new();
+        let mut previous = uf.memory_bytes();
+        assert_eq!(previous, 0);
+        for id in 0..100 {
+            uf.introduce_tag(Tag::from_raw(id));
+            let bytes = uf.memory_bytes();
+            assert!(bytes > previous, "{bytes} <= {previous} after {id}");
+            previous = bytes;
+        }
+        // Unions allocate nothing.
+        uf.union_tags(&Tag::from_raw(0), &Tag::from_raw(1));
+        assert_eq!(uf.memory_bytes(), previous);
+    }
+
+    #[test]
+    fn union_groups_rebuilds_exported_sets() {
+        let (mut uf, t) = singletons(7);
+        for (a, b) in [(0, 3), (3, 5), (1, 4)] {
+            uf.union_tags(&t[a], &t[b]);
+        }
+        let groups: Vec<Vec<Tag>> = uf.sets().into_values().collect();
+
+        let mut rebuilt = UnionFind::new();
+        rebuilt.union_groups(&groups);
+        assert!(rebuilt.equivalent(&mut uf));
+        assert_eq!(rebuilt.num_sets(), 4);
+
+        // Overlapping groups are merged.
+        rebuilt.union_groups(&[
+            vec![t[5].clone(), t[6].clone()],
+            vec![t[6].clone(), t[1].clone()],
+        ]);
+        assert_eq!(rebuilt.num_sets(), 2);
     }
 }
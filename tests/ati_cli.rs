@@ -0,0 +1,60 @@
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const TRACE: &str = r#"{"op": "track", "site": "f", "var": "x", "tags": [1]}
+{"op": "track", "site": "f", "var": "y", "tags": [2]}
+{"op": "track", "site": "f", "var": "z", "tags": [3]}
+{"op": "union", "tags": [1, 2]}
+{"op": "update", "site": "f"}
+"#;
+
+/// Writes `trace` into a file of its own, and runs the `ati` binary on it.
+fn run_ati(name: &str, trace: &str, args: &[&str]) -> Output {
+    let path: PathBuf =
+        std::env::temp_dir().join(format!("ati-{}-{name}.jsonl", std::process::id()));
+    fs::write(&path, trace).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_ati"))
+        .args(args)
+        .stdin(Stdio::from(File::open(&path).unwrap()))
+        .output()
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+#[test]
+fn reports_a_replayed_trace() {
+    let output = run_ati("report", TRACE, &[]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "1 sites, 3 variables, 2 abstract types\n\n=== f === \nx -> T0\ny -> T0\nz -> T1\n\n\n"
+    );
+}
+
+#[test]
+fn exports_a_replayed_trace_as_json() {
+    let output = run_ati("json", TRACE, &["--json"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "[{\"name\":\"f\",\"variables\":{\"x\":0,\"y\":0,\"z\":1}}]\n"
+    );
+}
+
+#[test]
+fn rejects_malformed_traces() {
+    let trace = "{\"op\": \"update\", \"site\": \"f\"}\n{\"op\": \"jump\"}\n";
+    let output = run_ati("malformed", trace, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+    assert!(
+        String::from_utf8(output.stderr)
+            .unwrap()
+            .starts_with("ati: line 2: ")
+    );
+
+    let output = run_ati("usage", TRACE, &["--yaml"]);
+    assert_eq!(output.status.code(), Some(1));
+}